@@ -1,5 +1,6 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { BandwidthLimit } from "./BandwidthLimit";
 import type { Lease } from "./Lease";
 import type { Rule } from "./Rule";
 
-export interface Client { id: number | null, ip: string, name: string, rules: Array<Rule>, leases: Array<Lease>, mac_address?: string }
\ No newline at end of file
+export interface Client { id: number | null, ip: string, name: string, rules: Array<Rule>, leases: Array<Lease>, mac_address?: string, bandwidth?: BandwidthLimit, safe_search?: boolean, }
\ No newline at end of file
@@ -1,3 +1,9 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 
-export interface DomainList { id: number | null, name: string, domains: Array<string>, }
\ No newline at end of file
+export interface DomainList { id: number | null, name: string, kind?: ListKind, domains: Array<string>, includes?: Array<number>, source?: ListSource, }
+
+export type ListKind = "dstdomain" | "dstdom_regex" | "url_regex" | "urlpath_regex";
+
+export interface ListSource { location: string, format: ListFormat, refresh_minutes: number, etag?: string, last_checked_utc?: number, last_updated_utc?: number, last_error?: string, }
+
+export type ListFormat = "hosts" | "adblock" | "plain" | "csv";
@@ -1,4 +1,6 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 import type { Rule } from "./Rule";
 
-export interface Lease { end_date_utc: number | null, end_date: string | null, rule: Rule, }
\ No newline at end of file
+export interface Lease { id?: number, start_date_utc?: number, end_date_utc: number | null, end_date: string | null, recurrence?: Recurrence, rule: Rule, granted_by?: string, created_date_utc?: number, }
+
+export type Recurrence = "daily" | "weekly";
\ No newline at end of file
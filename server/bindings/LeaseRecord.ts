@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Lease } from "./Lease";
+
+export interface LeaseRecord { id?: number, client_id: number | null, lease: Lease, ended_date_utc: number, revoked: boolean, }
@@ -1,9 +1,15 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 
-export interface Rule { kind: RuleKind, domainlists: Array<number>, }
+export interface Rule { kind: RuleKind, domainlists: Array<number>, domains?: Array<string>, exception_domains?: Array<string>, exception_domainlists?: Array<number>, mime_types?: Array<string>, file_extensions?: Array<string>, schedule?: Schedule, daily_quota_minutes?: number, }
 
 export enum RuleKind {
   ALLOW_HTTP_ACCESS = "allow_http_access",
-  DENY_HTTP_ACCESS = "deny_http_access"
+  DENY_HTTP_ACCESS = "deny_http_access",
+  QUOTA_HTTP_ACCESS = "quota_http_access"
 }
 
+export type Day = "sun" | "mon" | "tue" | "wed" | "thu" | "fri" | "sat";
+
+export interface TimeRange { start: string, end: string, }
+
+export interface Schedule { days: Array<Day>, times: Array<TimeRange>, timezone?: string, }
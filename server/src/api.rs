@@ -6,7 +6,7 @@ use axum::extract::State;
 use axum::Router;
 use axum::{
   extract::{self, Path},
-  routing, Json,
+  routing, Extension, Json,
 };
 use axum::middleware;
 
@@ -14,17 +14,45 @@ use crate::auth::auth;
 
 pub fn api_routes() -> Router<AppState> {
   Router::new()
-    .nest("/v1/client", clients::routes())
+    .nest("/v1/client", clients::routes().merge(leases::routes()))
+    .nest("/v1/lease", global_leases::routes())
     .nest("/v1/domainlist", domains::routes())
     .nest("/v1/netaccess", netaccess::routes())
     .nest("/v1/logs/proxy", logs::proxy::routes())
     .nest("/v1/proxy", proxy::routes())
+    .nest("/v1/preview", preview::routes())
+}
+
+/// State for testing handlers, with the configuration kept in a temporary directory.
+#[cfg(test)]
+fn test_state(dir: &tempdir::TempDir) -> AppState {
+  use confique::Config;
+  use std::sync::{Arc, Mutex};
+
+  let mut app_config = crate::model::Conf::builder().load().unwrap();
+  app_config.config_dir = dir.path().join("config").display().to_string();
+  app_config.squid_config_dir = dir.path().join("squid").display().to_string();
+  // Nothing listens for regenerate events, so handlers don't touch the generated configuration.
+  let (events, _) = tokio::sync::mpsc::channel(10);
+  AppState {
+    events,
+    gen_config_lock: Arc::new(Mutex::new(crate::Generation::default())),
+    app_config,
+    unifi_client: Arc::new(tokio::sync::Mutex::new(None)),
+  }
 }
 
 mod clients {
+  use chrono::{DateTime, Duration, Utc};
 
+  use crate::auth::AuthedUser;
+  use crate::domain::normalize_domains;
+  use crate::history;
+  use crate::model::{DomainList, Lease, LeaseRecord, RuleKind};
+  use crate::quota::{get_client_quotas, QuotaStatus};
+  use crate::schedule::squid_time_specs;
 
-use super::*;
+  use super::*;
 
   pub(super) fn routes() -> Router<AppState> {
     Router::new()
@@ -33,6 +61,7 @@ use super::*;
       .route("/:id", routing::get(get).route_layer(middleware::from_fn(auth)))
       .route("/:id", routing::put(put).route_layer(middleware::from_fn(auth)))
       .route("/:id", routing::delete(delete).route_layer(middleware::from_fn(auth)))
+      .route("/:id/quota", routing::get(get_quota).route_layer(middleware::from_fn(auth)))
   }
 
   fn check<F, S: Into<String>>(test: F, message: S) -> Result<()>
@@ -55,7 +84,9 @@ use super::*;
       .collect()
   }
 
-  fn validate(clients: &JsonRestList<Client>, client: &Client) -> Result<()> {
+  /// Checks that a client can be saved, putting the domains its rules and leases list into normal
+  /// form.
+  pub(super) fn validate(clients: &JsonRestList<Client>, client: &mut Client) -> Result<()> {
     check(
       || client.name.trim().is_empty(),
       "Client name must not be empty",
@@ -79,10 +110,118 @@ use super::*;
       format!("A client with name '{}' already exists.", client.name),
     )?;
 
+    for rule in client.rules.iter_mut() {
+      check(
+        || rule.kind == RuleKind::QuotaHttpAccess && rule.daily_quota_minutes.is_none(),
+        "Quota rules must have a daily_quota_minutes",
+      )?;
+      check(
+        || !rule.domains.is_empty(),
+        "Only leases can list individual domains. Use a domain list instead",
+      )?;
+      check(
+        || {
+          rule.kind == RuleKind::AllowHttpAccess
+            && (!rule.exception_domains.is_empty() || !rule.exception_domainlists.is_empty())
+        },
+        "Only deny and quota rules can have exceptions",
+      )?;
+      rule.exception_domains = normalize_domains(&rule.exception_domains).map_err(|invalid| {
+        MyError::BadRequestDetails("Some of the rule's exceptions are invalid".to_owned(), serde_json::json!(invalid))
+      })?;
+      check(
+        || {
+          rule.kind != RuleKind::DenyHttpAccess
+            && (!rule.mime_types.is_empty() || !rule.file_extensions.is_empty())
+        },
+        "Only deny rules can have content types and file extensions",
+      )?;
+      if let Some(mime_type) = rule.mime_types.iter().find(|m| !is_valid_mime_type(m)) {
+        return Err(MyError::BadRequest(format!(
+          "Invalid content type '{}'. Use a type like 'video/mp4' or 'video/*'",
+          mime_type
+        )));
+      }
+      if let Some(extension) = rule.file_extensions.iter().find(|e| !is_valid_file_extension(e)) {
+        return Err(MyError::BadRequest(format!("Invalid file extension '{}'", extension)));
+      }
+      if let Some(schedule) = &rule.schedule {
+        squid_time_specs(schedule)
+          .map_err(|e| MyError::BadRequest(format!("Invalid schedule: {}", e)))?;
+      }
+    }
+
+    check(
+      || client.bandwidth.as_ref().is_some_and(|b| b.rate_kbps == 0),
+      "A bandwidth limit must have a rate_kbps above zero",
+    )?;
+
+    for lease in client.leases.iter_mut() {
+      validate_lease(lease)?;
+    }
+
+    Ok(())
+  }
+
+  fn is_valid_mime_type(mime_type: &str) -> bool {
+    let is_token = |s: &str| {
+      !s.is_empty()
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '+' | '-'))
+    };
+    match mime_type.split_once('/') {
+      Some((media_type, subtype)) => is_token(media_type) && (subtype == "*" || is_token(subtype)),
+      None => false,
+    }
+  }
+
+  fn is_valid_file_extension(extension: &str) -> bool {
+    let extension = extension.strip_prefix('.').unwrap_or(extension);
+    !extension.is_empty() && extension.chars().all(|c| c.is_ascii_alphanumeric())
+  }
+
+  /// Checks that a lease can be saved, putting the domains it lists into normal form.
+  pub(super) fn validate_lease(lease: &mut Lease) -> Result<()> {
+    let end = lease
+      .end_date_utc
+      .ok_or_else(|| MyError::BadRequest("Leases must have an end_date_utc".to_owned()))?;
+    check(
+      || lease.rule.kind == RuleKind::QuotaHttpAccess,
+      "Leases can only allow or deny access",
+    )?;
+    check(
+      || !lease.rule.exception_domains.is_empty() || !lease.rule.exception_domainlists.is_empty(),
+      "Leases can't have exceptions",
+    )?;
+    check(
+      || !lease.rule.mime_types.is_empty() || !lease.rule.file_extensions.is_empty(),
+      "Leases can't have content types or file extensions",
+    )?;
+    lease.rule.domains = normalize_domains(&lease.rule.domains).map_err(|invalid| {
+      MyError::BadRequestDetails("Some of the lease's domains are invalid".to_owned(), serde_json::json!(invalid))
+    })?;
+    check(
+      || lease.start_date_utc.is_some_and(|start| start >= end),
+      "A lease must start before it ends",
+    )?;
+    if let Some(recurrence) = lease.recurrence {
+      check(
+        || lease.start_date_utc.is_none(),
+        "Recurring leases must have a start_date_utc",
+      )?;
+      check(
+        || {
+          lease
+            .start_date_utc
+            .is_some_and(|start| end - start > Duration::days(recurrence.days()))
+        },
+        "A recurring lease can't last longer than the time between recurrences",
+      )?;
+    }
+
     Ok(())
   }
 
-  fn load(state: &AppState) -> anyhow::Result<JsonRestList<Client>> {
+  pub(super) fn load(state: &AppState) -> anyhow::Result<JsonRestList<Client>> {
     JsonRestList::<Client>::load(state.app_config.clients_json())
   }
 
@@ -94,42 +233,477 @@ use super::*;
     load(&state)?.get(id)
   }
 
+  async fn get_quota(
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+  ) -> Result<Json<Vec<QuotaStatus>>> {
+    let client = load(&state)?.get(id)?;
+    let domains = JsonRestList::<DomainList>::load(state.app_config.domains_json())?;
+
+    Ok(Json(get_client_quotas(&state.app_config, &client, &domains.list)?))
+  }
+
+  /// Records who granted the leases a client has that it didn't have before, and when, and keeps
+  /// that record for the leases it already had. Leases are matched by id, so this has to happen
+  /// before new leases are given ids.
+  fn stamp_leases(old: &[Lease], client: &mut Client, user: Option<&AuthedUser>, now: DateTime<Utc>) {
+    for lease in client.leases.iter_mut() {
+      match old.iter().find(|o| lease.id.is_some() && o.id == lease.id) {
+        Some(old) => {
+          lease.granted_by = old.granted_by.clone();
+          lease.created_date_utc = old.created_date_utc;
+        }
+        None => {
+          lease.granted_by = user.map(|u| u.email.clone());
+          lease.created_date_utc = Some(now);
+        }
+      }
+    }
+  }
+
+  /// Adds the leases a client no longer has to the history. They count as revoked unless they had
+  /// already ended and were only waiting to be cleaned up.
+  fn archive_removed_leases(state: &AppState, id: u32, removed: Vec<Lease>, now: DateTime<Utc>) -> anyhow::Result<()> {
+    let records = removed
+      .into_iter()
+      .map(|l| {
+        let revoked = !l.is_expired(now);
+        LeaseRecord::new(Some(id), l, now, revoked)
+      })
+      .collect();
+    history::archive(&state.app_config, records)
+  }
+
   async fn put(
     State(state): State<AppState>,
     Path(id): Path<u32>,
-    extract::Json(client): extract::Json<Client>,
+    user: Option<Extension<AuthedUser>>,
+    extract::Json(mut client): extract::Json<Client>,
   ) -> Result<Json<Client>> {
-    let clients = load(&state)?;
+    let mut clients = load(&state)?;
+    let Json(old) = clients.get(id)?;
 
-    validate(&clients, &client)?;
-    let result = load(&state)?.put(id, client.clone());
+    let now = Utc::now();
+    let removed: Vec<_> = old
+      .leases
+      .iter()
+      .filter(|o| !client.leases.iter().any(|l| l.id == o.id))
+      .cloned()
+      .collect();
+    stamp_leases(&old.leases, &mut client, user.as_ref().map(|Extension(u)| u), now);
+    client.assign_lease_ids();
+    validate(&clients, &mut client)?;
+    let result = clients.put(id, client)?;
+    archive_removed_leases(&state, id, removed, now)?;
     state.regenerate().await;
 
-    result
+    Ok(result)
   }
 
   async fn delete(State(state): State<AppState>, Path(id): Path<u32>) -> Result<Json<Client>> {
-    let result = load(&state)?.delete(id);
+    let result = load(&state)?.delete(id)?;
+    archive_removed_leases(&state, id, result.leases.clone(), Utc::now())?;
     state.regenerate().await;
 
-    result
+    Ok(result)
   }
 
   async fn post(
     State(state): State<AppState>,
-    extract::Json(client): extract::Json<Client>,
+    user: Option<Extension<AuthedUser>>,
+    extract::Json(mut client): extract::Json<Client>,
   ) -> Result<Json<Client>> {
     let mut clients = load(&state)?;
-    validate(&clients, &client)?;
+    stamp_leases(&[], &mut client, user.as_ref().map(|Extension(u)| u), Utc::now());
+    client.assign_lease_ids();
+    validate(&clients, &mut client)?;
     let result = clients.add(client.clone());
     state.regenerate().await;
 
     result
   }
+
+  #[cfg(test)]
+  mod tests {
+    use super::*;
+    use crate::api::test_state;
+
+    #[tokio::test]
+    async fn normalizes_exceptions() -> anyhow::Result<()> {
+      let dir = tempdir::TempDir::new("penguin-api-test")?;
+      let state = test_state(&dir);
+      let client: Client = serde_json::from_value(serde_json::json!({
+        "ip": "192.168.1.33",
+        "name": "Laptop",
+        "rules": [{
+          "kind": "deny_http_access",
+          "exception_domains": ["https://Classroom.Google.com/c/123", "*.khanacademy.org"]
+        }],
+        "leases": []
+      }))?;
+
+      let Json(client) = post(State(state.clone()), None, extract::Json(client)).await.unwrap();
+      let expected = vec!["classroom.google.com", ".khanacademy.org"];
+      assert_eq!(client.rules[0].exception_domains, expected);
+      let Json(saved) = load(&state)?.get(client.id.unwrap()).unwrap();
+      assert_eq!(saved.rules[0].exception_domains, expected);
+
+      Ok(())
+    }
+
+    #[tokio::test]
+    async fn tracks_leases() -> anyhow::Result<()> {
+      let dir = tempdir::TempDir::new("penguin-api-test")?;
+      let state = test_state(&dir);
+      let end = Utc::now() + Duration::hours(1);
+      let lease = |id: Option<u32>| serde_json::json!({
+        "id": id,
+        "end_date_utc": end.timestamp_millis(),
+        "rule": { "kind": "allow_http_access", "domains": ["example.com"] }
+      });
+      let client = |id: Option<u32>, leases: Vec<serde_json::Value>| -> Client {
+        serde_json::from_value(serde_json::json!({
+          "id": id,
+          "ip": "192.168.1.33",
+          "name": "Laptop",
+          "rules": [],
+          "leases": leases
+        })).unwrap()
+      };
+      let user = || Some(Extension(AuthedUser { email: "parent@example.com".to_owned() }));
+
+      let Json(saved) = post(State(state.clone()), user(), extract::Json(client(None, vec![lease(None), lease(None)]))).await.unwrap();
+      let id = saved.id.unwrap();
+      assert!(saved.leases.iter().all(|l| l.granted_by.as_deref() == Some("parent@example.com")));
+      assert!(saved.leases.iter().all(|l| l.created_date_utc.is_some()));
+
+      // Lease 2 is revoked and a new one added. The new one mustn't be mistaken for lease 2.
+      let Json(saved) = put(State(state.clone()), Path(id), None, extract::Json(client(Some(id), vec![lease(Some(1)), lease(None)]))).await.unwrap();
+      assert_eq!(saved.leases.iter().map(|l| l.id).collect::<Vec<_>>(), vec![Some(1), Some(2)]);
+      assert_eq!(saved.leases[0].granted_by.as_deref(), Some("parent@example.com"));
+      assert_eq!(saved.leases[1].granted_by, None);
+      let history = history::load(&state.app_config)?;
+      assert_eq!(history.list.items.len(), 1);
+      assert!(history.list.items[0].revoked);
+      assert_eq!(history.list.items[0].client_id, Some(id));
+
+      let _ = delete(State(state.clone()), Path(id)).await.unwrap();
+      let history = history::load(&state.app_config)?;
+      assert_eq!(history.list.items.len(), 3);
+      assert!(history.list.items.iter().all(|r| r.revoked));
+
+      Ok(())
+    }
+  }
+}
+
+mod leases {
+  use chrono::serde::ts_milliseconds_option;
+  use chrono::{DateTime, Duration, Utc};
+  use serde::{Deserialize, Serialize};
+
+  use super::clients::{load, validate};
+  use super::*;
+  use crate::auth::AuthedUser;
+  use crate::history::{self, Summary};
+  use crate::model::{Lease, LeaseRecord, Recurrence, Rule};
+
+  pub(super) fn routes() -> Router<AppState> {
+    Router::new()
+      .route("/:id/leases", routing::get(get_all).route_layer(middleware::from_fn(auth)))
+      .route("/:id/leases", routing::post(post).route_layer(middleware::from_fn(auth)))
+      .route("/:id/leases/history", routing::get(get_history).route_layer(middleware::from_fn(auth)))
+      .route("/:id/leases/summary", routing::get(get_summary).route_layer(middleware::from_fn(auth)))
+      .route("/:id/leases/:lease_id", routing::delete(revoke).route_layer(middleware::from_fn(auth)))
+      .route("/:id/leases/:lease_id/extend", routing::post(extend).route_layer(middleware::from_fn(auth)))
+      .route("/:id/leases/:lease_id/shorten", routing::post(shorten).route_layer(middleware::from_fn(auth)))
+  }
+
+  #[derive(Serialize)]
+  pub struct LeaseStatus {
+    #[serde(flatten)]
+    lease: Lease,
+    /// Whether the lease is in effect right now.
+    active: bool,
+    /// How much longer the lease is in effect for, or how long it will last if it hasn't started.
+    remaining_seconds: i64,
+  }
+
+  impl LeaseStatus {
+    fn new(lease: &Lease, now: DateTime<Utc>) -> Self {
+      LeaseStatus {
+        lease: lease.clone(),
+        active: lease.is_active(now),
+        remaining_seconds: lease.remaining(now).num_seconds(),
+      }
+    }
+  }
+
+  /// A new lease, which ends either at a given time or after a given duration.
+  #[derive(Deserialize)]
+  struct NewLease {
+    #[serde(with = "ts_milliseconds_option", default)]
+    start_date_utc: Option<DateTime<Utc>>,
+    #[serde(with = "ts_milliseconds_option", default)]
+    end_date_utc: Option<DateTime<Utc>>,
+    duration_minutes: Option<u32>,
+    recurrence: Option<Recurrence>,
+    rule: Rule,
+  }
+
+  #[derive(Deserialize)]
+  struct Adjustment {
+    minutes: u32,
+  }
+
+  async fn get_all(State(state): State<AppState>, Path(id): Path<u32>) -> Result<Json<Vec<LeaseStatus>>> {
+    let client = load(&state)?.get(id)?;
+    let now = Utc::now();
+
+    Ok(Json(client.leases.iter().map(|l| LeaseStatus::new(l, now)).collect()))
+  }
+
+  /// Gets the leases the client had that have ended, oldest first.
+  async fn get_history(State(state): State<AppState>, Path(id): Path<u32>) -> Result<Json<Vec<LeaseRecord>>> {
+    let _ = load(&state)?.get(id)?;
+    let history = history::load(&state.app_config)?;
+
+    Ok(Json(history.list.items.into_iter().filter(|r| r.client_id == Some(id)).collect()))
+  }
+
+  /// Summarises the client's leases that ended in the last week.
+  async fn get_summary(State(state): State<AppState>, Path(id): Path<u32>) -> Result<Json<Summary>> {
+    let _ = load(&state)?.get(id)?;
+    let history = history::load(&state.app_config)?;
+    let records = history.list.items.iter().filter(|r| r.client_id == Some(id));
+
+    Ok(Json(history::weekly_summary(records, Utc::now())))
+  }
+
+  #[allow(deprecated)]
+  async fn post(
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+    user: Option<Extension<AuthedUser>>,
+    extract::Json(new_lease): extract::Json<NewLease>,
+  ) -> Result<Json<LeaseStatus>> {
+    let now = Utc::now();
+    let end_date_utc = match (new_lease.end_date_utc, new_lease.duration_minutes) {
+      (Some(end), None) => end,
+      (None, Some(minutes)) => new_lease.start_date_utc.unwrap_or(now) + Duration::minutes(minutes.into()),
+      _ => return Err(MyError::BadRequest("Specify one of end_date_utc or duration_minutes".to_owned())),
+    };
+
+    let mut clients = load(&state)?;
+    let Json(mut client) = clients.get(id)?;
+    client.leases.push(Lease {
+      id: None,
+      start_date_utc: new_lease.start_date_utc,
+      end_date_utc: Some(end_date_utc),
+      end_date: None,
+      recurrence: new_lease.recurrence,
+      rule: new_lease.rule,
+      granted_by: user.map(|Extension(user)| user.email),
+      created_date_utc: Some(now),
+    });
+    client.assign_lease_ids();
+    validate(&clients, &mut client)?;
+
+    let status = LeaseStatus::new(client.leases.last().unwrap(), now);
+    let _ = clients.put(id, client)?;
+    state.regenerate().await;
+
+    Ok(Json(status))
+  }
+
+  /// Changes one of a client's leases, then saves the client and regenerates.
+  async fn update<F>(state: &AppState, id: u32, lease_id: u32, change: F) -> Result<Json<LeaseStatus>>
+  where
+    F: FnOnce(&mut Lease, DateTime<Utc>),
+  {
+    let mut clients = load(state)?;
+    let Json(mut client) = clients.get(id)?;
+    client.assign_lease_ids();
+
+    let now = Utc::now();
+    let lease = client.leases.iter_mut().find(|l| l.id == Some(lease_id)).ok_or(MyError::NotFound)?;
+    change(lease, now);
+    let status = LeaseStatus::new(lease, now);
+
+    validate(&clients, &mut client)?;
+    let _ = clients.put(id, client)?;
+    state.regenerate().await;
+
+    Ok(Json(status))
+  }
+
+  async fn extend(
+    State(state): State<AppState>,
+    Path((id, lease_id)): Path<(u32, u32)>,
+    extract::Json(adjustment): extract::Json<Adjustment>,
+  ) -> Result<Json<LeaseStatus>> {
+    update(&state, id, lease_id, |lease, now| {
+      if let Some(end) = lease.end_date_utc {
+        // Extending a lease that has already ended (but not been cleaned up yet) starts from now.
+        let from = if lease.recurrence.is_some() { end } else { end.max(now) };
+        lease.end_date_utc = Some(from + Duration::minutes(adjustment.minutes.into()));
+      }
+    }).await
+  }
+
+  async fn shorten(
+    State(state): State<AppState>,
+    Path((id, lease_id)): Path<(u32, u32)>,
+    extract::Json(adjustment): extract::Json<Adjustment>,
+  ) -> Result<Json<LeaseStatus>> {
+    update(&state, id, lease_id, |lease, now| {
+      if let Some(end) = lease.end_date_utc {
+        let end = end - Duration::minutes(adjustment.minutes.into());
+        lease.end_date_utc = Some(if lease.recurrence.is_some() { end } else { end.max(now) });
+      }
+    }).await
+  }
+
+  async fn revoke(
+    State(state): State<AppState>,
+    Path((id, lease_id)): Path<(u32, u32)>,
+  ) -> Result<Json<LeaseStatus>> {
+    let mut clients = load(&state)?;
+    let Json(mut client) = clients.get(id)?;
+    client.assign_lease_ids();
+
+    let pos = client.leases.iter().position(|l| l.id == Some(lease_id)).ok_or(MyError::NotFound)?;
+    let lease = client.leases.remove(pos);
+    history::archive(&state.app_config, vec![LeaseRecord::new(Some(id), lease.clone(), Utc::now(), true)])?;
+    let _ = clients.put(id, client)?;
+    state.regenerate().await;
+
+    Ok(Json(LeaseStatus {
+      lease,
+      active: false,
+      remaining_seconds: 0,
+    }))
+  }
+}
+
+/// Leases that apply to all clients.
+mod global_leases {
+  use chrono::Utc;
+
+  use super::clients::validate_lease;
+  use super::*;
+  use crate::auth::AuthedUser;
+  use crate::history;
+  use crate::model::{Lease, LeaseRecord};
+
+  pub(super) fn routes() -> Router<AppState> {
+    Router::new()
+      .route("/", routing::get(get_all).route_layer(middleware::from_fn(auth)))
+      .route("/", routing::post(post).route_layer(middleware::from_fn(auth)))
+      .route("/history", routing::get(get_history).route_layer(middleware::from_fn(auth)))
+      .route("/:id", routing::get(get).route_layer(middleware::from_fn(auth)))
+      .route("/:id", routing::put(put).route_layer(middleware::from_fn(auth)))
+      .route("/:id", routing::delete(delete).route_layer(middleware::from_fn(auth)))
+  }
+
+  pub(super) fn load(state: &AppState) -> anyhow::Result<JsonRestList<Lease>> {
+    JsonRestList::<Lease>::load(state.app_config.leases_json())
+  }
+
+  async fn get_all(State(state): State<AppState>) -> Result<Json<Vec<Lease>>> {
+    load(&state)?.get_all()
+  }
+
+  async fn get(State(state): State<AppState>, Path(id): Path<u32>) -> Result<Json<Lease>> {
+    load(&state)?.get(id)
+  }
+
+  async fn put(
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+    extract::Json(mut lease): extract::Json<Lease>,
+  ) -> Result<Json<Lease>> {
+    validate_lease(&mut lease)?;
+    let result = load(&state)?.put(id, lease);
+    state.regenerate().await;
+
+    result
+  }
+
+  /// Gets the leases for all clients that have ended, oldest first.
+  async fn get_history(State(state): State<AppState>) -> Result<Json<Vec<LeaseRecord>>> {
+    let history = history::load(&state.app_config)?;
+
+    Ok(Json(history.list.items.into_iter().filter(|r| r.client_id.is_none()).collect()))
+  }
+
+  async fn delete(State(state): State<AppState>, Path(id): Path<u32>) -> Result<Json<Lease>> {
+    let result = load(&state)?.delete(id);
+    if let Ok(Json(lease)) = &result {
+      history::archive(&state.app_config, vec![LeaseRecord::new(None, lease.clone(), Utc::now(), true)])?;
+    }
+    state.regenerate().await;
+
+    result
+  }
+
+  async fn post(
+    State(state): State<AppState>,
+    user: Option<Extension<AuthedUser>>,
+    extract::Json(mut lease): extract::Json<Lease>,
+  ) -> Result<Json<Lease>> {
+    lease.granted_by = user.map(|Extension(user)| user.email);
+    lease.created_date_utc = Some(Utc::now());
+    validate_lease(&mut lease)?;
+    let result = load(&state)?.add(lease);
+    state.regenerate().await;
+
+    result
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use super::*;
+    use crate::api::test_state;
+
+    #[tokio::test]
+    async fn normalizes_domains() -> anyhow::Result<()> {
+      let dir = tempdir::TempDir::new("penguin-api-test")?;
+      let state = test_state(&dir);
+      let lease: Lease = serde_json::from_value(serde_json::json!({
+        "end_date_utc": (Utc::now() + chrono::Duration::hours(1)).timestamp_millis(),
+        "rule": {
+          "kind": "deny_http_access",
+          "domains": ["https://Example.com/path", "*.games.example.org"]
+        }
+      }))?;
+
+      let Json(lease) = post(State(state.clone()), None, extract::Json(lease)).await.unwrap();
+      assert_eq!(lease.rule.domains, vec!["example.com", ".games.example.org"]);
+      let Json(saved) = load(&state)?.get(lease.id.unwrap()).unwrap();
+      assert_eq!(saved.rule.domains, lease.rule.domains);
+
+      Ok(())
+    }
+  }
 }
 
 mod domains {
-  use crate::model::DomainList;
+  use std::collections::BTreeSet;
+
+  use axum::extract::Query;
+  use axum::http::header;
+  use axum::response::IntoResponse;
+  use chrono::Utc;
+  use serde::{Deserialize, Serialize};
+
+  use crate::domain::{
+    find_overlaps, flatten, merge_domains, normalize_list, resolve_includes, ImportReport, ListMatcher, Overlap,
+  };
+  use crate::list::IdentifiedList;
+  use crate::listformat;
+  use crate::model::{DomainList, ListFormat, ListKind, Rule};
+  use crate::subscription;
 
   use super::*;
 
@@ -137,15 +711,292 @@ mod domains {
     Router::new()
       .route("/", routing::get(get_all).route_layer(middleware::from_fn(auth)))
       .route("/", routing::post(post).route_layer(middleware::from_fn(auth)))
+      .route("/overlaps", routing::get(get_overlaps).route_layer(middleware::from_fn(auth)))
+      .route("/:id/refresh", routing::post(refresh).route_layer(middleware::from_fn(auth)))
+      .route("/:id/export", routing::get(export).route_layer(middleware::from_fn(auth)))
+      .route("/import", routing::post(import).route_layer(middleware::from_fn(auth)))
+      .route("/lookup", routing::get(lookup).route_layer(middleware::from_fn(auth)))
       .route("/:id", routing::get(get).route_layer(middleware::from_fn(auth)))
-      .route("/:id", routing::put(put))
+      .route("/:id", routing::put(put).route_layer(middleware::from_fn(auth)))
       .route("/:id", routing::delete(delete).route_layer(middleware::from_fn(auth)))
   }
 
-  fn load(state: &AppState) -> anyhow::Result<JsonRestList<DomainList>> {
+  pub(super) fn load(state: &AppState) -> anyhow::Result<JsonRestList<DomainList>> {
     JsonRestList::<DomainList>::load(state.app_config.domains_json())
   }
 
+  /// The domain lists a rule refers to.
+  pub(super) fn rule_lists(rule: &Rule) -> impl Iterator<Item = u32> + '_ {
+    rule.domainlists.iter().chain(rule.exception_domainlists.iter()).copied()
+  }
+
+  /// The domain lists a client's rules, leases and bandwidth limit refer to.
+  pub(super) fn client_lists(client: &Client) -> BTreeSet<u32> {
+    client
+      .rules
+      .iter()
+      .chain(client.leases.iter().map(|l| &l.rule))
+      .flat_map(rule_lists)
+      .chain(client.bandwidth.iter().flat_map(|b| b.domainlists.iter().copied()))
+      .collect()
+  }
+
+  /// Puts the list's entries into the form squid expects, rejecting it if any aren't valid domains
+  /// or regular expressions. The outcome of fetching the list's source is kept from the stored list,
+  /// if there is one, unless the source has changed.
+  pub(super) fn normalize(state: &AppState, list: &mut DomainList, stored: Option<&DomainList>) -> Result<()> {
+    list.domains = normalize_list(list).map_err(|invalid| {
+      MyError::BadRequestDetails("Some entries are invalid".to_owned(), serde_json::json!(invalid))
+    })?;
+
+    if let Some(source) = list.source.as_mut() {
+      if !list.kind.is_dstdomain() {
+        return Err(MyError::BadRequest("Only dstdomain lists can have a source".to_owned()));
+      }
+      if source.location.trim().is_empty() {
+        return Err(MyError::BadRequest("A list's source must have a location".to_owned()));
+      }
+      if !subscription::is_url(&source.location) {
+        subscription::local_path(&state.app_config.config_path(), &source.location)
+          .map_err(|e| MyError::BadRequest(format!("{:#}", e)))?;
+      }
+      if source.refresh_minutes < subscription::MIN_REFRESH_MINUTES {
+        return Err(MyError::BadRequest(format!(
+          "A list's source can't be refreshed more often than every {} minutes",
+          subscription::MIN_REFRESH_MINUTES
+        )));
+      }
+      match stored.and_then(|l| l.source.as_ref()) {
+        Some(stored) if stored.location == source.location && stored.format == source.format => {
+          source.etag = stored.etag.clone();
+          source.last_checked_utc = stored.last_checked_utc;
+          source.last_updated_utc = stored.last_updated_utc;
+          source.last_error = stored.last_error.clone();
+        }
+        // Fetch the source again soon, in case it's changed.
+        _ => {
+          source.etag = None;
+          source.last_checked_utc = None;
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Checks that the lists a list includes exist and are of the same kind, and that no lists would
+  /// end up including each other.
+  pub(super) fn check_includes(lists: &IdentifiedList<DomainList>, id: Option<u32>, list: &DomainList) -> Result<()> {
+    for include in list.includes.iter() {
+      if id == Some(*include) {
+        return Err(MyError::BadRequest("A list can't include itself".to_owned()));
+      }
+      let included = lists.items.iter().find(|l| l.id == Some(*include)).ok_or_else(|| {
+        MyError::BadRequest(format!("Domain list {} doesn't exist", include))
+      })?;
+      if included.kind != list.kind {
+        return Err(MyError::BadRequest(format!("Domain list {} isn't the same kind of list", include)));
+      }
+    }
+
+    let Some(id) = id else {
+      return Ok(());
+    };
+    if lists.items.iter().any(|l| l.includes.contains(&id) && l.kind != list.kind) {
+      return Err(MyError::BadRequest("The list is included by a list of another kind".to_owned()));
+    }
+    let mut list = list.clone();
+    list.id = Some(id);
+    let updated = IdentifiedList::new(
+      lists.items.iter().map(|l| if l.id == Some(id) { list.clone() } else { l.clone() }).collect(),
+    );
+    resolve_includes(&updated, id).map_err(MyError::BadRequest)?;
+
+    Ok(())
+  }
+
+  /// A list that contains a domain, either directly or through a list it includes.
+  #[derive(Serialize)]
+  struct Containing {
+    id: Option<u32>,
+    name: String,
+    /// The included list that contains the domain, if the list doesn't contain it directly.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    via: Option<u32>,
+  }
+
+  #[derive(Deserialize)]
+  struct LookupQuery {
+    domain: String,
+  }
+
+  /// Finds the lists that contain a domain.
+  async fn lookup(State(state): State<AppState>, Query(query): Query<LookupQuery>) -> Result<Json<Vec<Containing>>> {
+    let lists = load(&state)?.list;
+
+    let mut result = Vec::new();
+    for list in lists.items.iter() {
+      let Some(id) = list.id else {
+        continue;
+      };
+      let members = resolve_includes(&lists, id).map_err(MyError::BadRequest)?;
+      if let Some(member) = members.iter().find(|m| ListMatcher::new(m).matches(&query.domain)) {
+        result.push(Containing {
+          id: list.id,
+          name: list.name.clone(),
+          via: member.id.filter(|m| *m != id),
+        });
+      }
+    }
+
+    Ok(Json(result))
+  }
+
+  #[derive(Deserialize)]
+  struct ExportQuery {
+    format: ListFormat,
+  }
+
+  /// Entries to import, either into a new list with the given name or into an existing list.
+  #[derive(Deserialize)]
+  struct Import {
+    list_id: Option<u32>,
+    name: Option<String>,
+    format: ListFormat,
+    content: String,
+    /// If set, just report what would happen without changing anything.
+    #[serde(default)]
+    preview: bool,
+  }
+
+  #[derive(Serialize)]
+  struct ImportResult {
+    #[serde(flatten)]
+    report: ImportReport,
+    /// The list the entries were imported into, or would be.
+    list: DomainList,
+  }
+
+  async fn export(
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+    Query(query): Query<ExportQuery>,
+  ) -> Result<impl IntoResponse> {
+    let lists = load(&state)?;
+    let Json(list) = lists.get(id)?;
+    // Whoever the list is shared with won't have the lists it includes.
+    let list = flatten(&lists.list, &list).map_err(MyError::BadRequest)?;
+    let (content_type, extension) = match query.format {
+      ListFormat::Hosts | ListFormat::Adblock if !list.kind.is_dstdomain() => {
+        return Err(MyError::BadRequest("Only dstdomain lists can be exported in this format".to_owned()))
+      }
+      ListFormat::Csv => ("text/csv", "csv"),
+      _ => ("text/plain", "txt"),
+    };
+
+    Ok((
+      [
+        (header::CONTENT_TYPE, content_type.to_owned()),
+        (header::CONTENT_DISPOSITION, format!("attachment; filename=\"domains_{:04}.{}\"", id, extension)),
+      ],
+      listformat::export(query.format, &list),
+    ))
+  }
+
+  async fn import(
+    State(state): State<AppState>,
+    extract::Json(import): extract::Json<Import>,
+  ) -> Result<Json<ImportResult>> {
+    let mut lists = load(&state)?;
+    let mut list = match (import.list_id, import.name) {
+      (Some(id), None) => lists.get(id)?.0,
+      (None, Some(name)) if !name.trim().is_empty() => DomainList {
+        id: None,
+        name,
+        kind: ListKind::Dstdomain,
+        domains: vec![],
+        includes: vec![],
+        source: None,
+      },
+      _ => return Err(MyError::BadRequest("Specify one of list_id or name".to_owned())),
+    };
+    if !list.kind.is_dstdomain() || list.source.is_some() {
+      return Err(MyError::BadRequest("Domains can only be imported into dstdomain lists without a source".to_owned()));
+    }
+
+    let (domains, report) = merge_domains(&list.domains, &listformat::parse(import.format, &import.content));
+    list.domains = domains;
+    if import.preview {
+      return Ok(Json(ImportResult { report, list }));
+    }
+
+    let Json(list) = match list.id {
+      Some(id) => {
+        let result = lists.put(id, list)?;
+        state.regenerate().await;
+        result
+      }
+      None => lists.add(list)?,
+    };
+
+    Ok(Json(ImportResult { report, list }))
+  }
+
+  /// Fetches a list's source now, rather than waiting until it's next due.
+  async fn refresh(State(state): State<AppState>, Path(id): Path<u32>) -> Result<Json<DomainList>> {
+    let Json(mut list) = load(&state)?.get(id)?;
+    if list.source.is_none() {
+      return Err(MyError::BadRequest("The list doesn't have a source".to_owned()));
+    }
+
+    let changed = subscription::refresh(&state.app_config.config_path(), &mut list, Utc::now()).await;
+    subscription::store(&state.app_config, vec![list.clone()])?;
+    if changed {
+      state.regenerate().await;
+    }
+
+    Ok(Json(list))
+  }
+
+  /// Finds domains in one list that are covered by another list used by the same client.
+  async fn get_overlaps(State(state): State<AppState>) -> Result<Json<Vec<Overlap>>> {
+    let domainlists = load(&state)?;
+    let clients = super::clients::load(&state)?;
+
+    let mut result: Vec<Overlap> = Vec::new();
+    for client in clients.list.items.iter() {
+      let ids: BTreeSet<_> = client
+        .rules
+        .iter()
+        .chain(client.leases.iter().map(|l| &l.rule))
+        .flat_map(|r| r.domainlists.iter())
+        .collect();
+      let lists = domainlists
+        .list
+        .items
+        .iter()
+        .filter(|l| l.id.is_some_and(|id| ids.contains(&id)))
+        .map(|l| flatten(&domainlists.list, l))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(MyError::BadRequest)?;
+
+      // A list naturally overlaps with the lists it includes.
+      let includes = |a: Option<u32>, b: Option<u32>| {
+        a.and_then(|a| resolve_includes(&domainlists.list, a).ok())
+          .is_some_and(|members| members.iter().any(|m| m.id == b))
+      };
+
+      for overlap in find_overlaps(&lists.iter().collect::<Vec<_>>()) {
+        let (a, b) = (overlap.domainlist, overlap.covered_by_domainlist);
+        if !includes(a, b) && !includes(b, a) && !result.contains(&overlap) {
+          result.push(overlap);
+        }
+      }
+    }
+
+    Ok(Json(result))
+  }
+
   async fn get_all(State(state): State<AppState>) -> Result<Json<Vec<DomainList>>> {
     load(&state)?.get_all()
   }
@@ -157,29 +1008,144 @@ mod domains {
   async fn put(
     State(state): State<AppState>,
     Path(id): Path<u32>,
-    extract::Json(client): extract::Json<DomainList>,
+    extract::Json(mut list): extract::Json<DomainList>,
   ) -> Result<Json<DomainList>> {
-    load(&state)?.put(id, client)
+    let mut lists = load(&state)?;
+    normalize(&state, &mut list, lists.list.items.iter().find(|l| l.id == Some(id)))?;
+    check_includes(&lists.list, Some(id), &list)?;
+    let result = lists.put(id, list)?;
+    state.regenerate().await;
+
+    Ok(result)
   }
 
   async fn delete(State(state): State<AppState>, Path(id): Path<u32>) -> Result<Json<DomainList>> {
-    load(&state)?.delete(id)
+    let mut lists = load(&state)?;
+    if let Some(including) = lists.list.items.iter().find(|l| l.includes.contains(&id)) {
+      return Err(MyError::BadRequest(format!("The list is included by '{}'", including.name)));
+    }
+    // Squid would reject a configuration that refers to a list that doesn't exist.
+    let clients = super::clients::load(&state)?;
+    if let Some(client) = clients.list.items.iter().find(|c| client_lists(c).contains(&id)) {
+      return Err(MyError::BadRequest(format!("The list is used by '{}'", client.name)));
+    }
+    let global_leases = super::global_leases::load(&state)?;
+    if let Some(lease) = global_leases.list.items.iter().find(|l| rule_lists(&l.rule).any(|l| l == id)) {
+      return Err(MyError::BadRequest(format!("The list is used by global lease {}", lease.id.unwrap())));
+    }
+    let result = lists.delete(id)?;
+    state.regenerate().await;
+
+    Ok(result)
   }
 
   async fn post(
     State(state): State<AppState>,
-    extract::Json(client): extract::Json<DomainList>,
+    extract::Json(mut list): extract::Json<DomainList>,
   ) -> Result<Json<DomainList>> {
-    let mut clients = load(&state)?;
+    normalize(&state, &mut list, None)?;
+    let mut lists = load(&state)?;
+    check_includes(&lists.list, None, &list)?;
+    let result = lists.add(list)?;
+    state.regenerate().await;
+
+    Ok(result)
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use super::*;
+    use crate::api::test_state;
+
+    #[tokio::test]
+    async fn keeps_lists_in_use() -> anyhow::Result<()> {
+      let dir = tempdir::TempDir::new("penguin-api-test")?;
+      let state = test_state(&dir);
+      std::fs::create_dir(state.app_config.config_path())?;
+
+      let mut lists = load(&state)?;
+      for name in ["Games", "Videos", "Unused"] {
+        lists.list.add(serde_json::from_value(serde_json::json!({ "name": name, "domains": ["example.com"] }))?);
+      }
+      lists.save()?;
+      let mut clients = super::super::clients::load(&state)?;
+      clients.list.add(serde_json::from_value(serde_json::json!({
+        "ip": "192.168.1.33",
+        "name": "Laptop",
+        "bandwidth": { "rate_kbps": 1000, "domainlists": [1] }
+      }))?);
+      clients.save()?;
+      let mut global_leases = super::super::global_leases::load(&state)?;
+      global_leases.list.add(serde_json::from_value(serde_json::json!({
+        "end_date_utc": null,
+        "rule": { "kind": "deny_http_access", "domainlists": [2] }
+      }))?);
+      global_leases.save()?;
+
+      for (id, message) in [(1, "The list is used by 'Laptop'"), (2, "The list is used by global lease 1")] {
+        match delete(State(state.clone()), Path(id)).await {
+          Err(MyError::BadRequest(m)) => assert_eq!(m, message),
+          _ => panic!("Deleted list {}", id),
+        }
+      }
+      let Json(deleted) = delete(State(state.clone()), Path(3)).await.unwrap();
+      assert_eq!(deleted.name, "Unused");
+      assert_eq!(load(&state)?.list.items.len(), 2);
+
+      Ok(())
+    }
 
-    clients.add(client)
+    #[tokio::test]
+    async fn keeps_subscription_state() -> anyhow::Result<()> {
+      let dir = tempdir::TempDir::new("penguin-api-test")?;
+      let state = test_state(&dir);
+      std::fs::create_dir(state.app_config.config_path())?;
+      std::fs::write(state.app_config.config_path().join("gambling.txt"), "gambling.example.com\n")?;
+      std::fs::write(state.app_config.config_path().join("casinos.txt"), "casino.example.com\n")?;
+      let list = |name: &str, location: &str, refresh_minutes: u32| -> DomainList {
+        serde_json::from_value(serde_json::json!({
+          "name": name,
+          "source": { "location": location, "format": "plain", "refresh_minutes": refresh_minutes }
+        }))
+        .unwrap()
+      };
+
+      match post(State(state.clone()), extract::Json(list("Gambling", "gambling.txt", 1))).await {
+        Err(MyError::BadRequest(m)) => assert_eq!(m, "A list's source can't be refreshed more often than every 15 minutes"),
+        _ => panic!("Accepted a list refreshed every minute"),
+      }
+
+      let Json(posted) = post(State(state.clone()), extract::Json(list("Gambling", "gambling.txt", 60))).await.unwrap();
+      let id = posted.id.unwrap();
+      let mut lists = load(&state)?;
+      let mut fetched = posted.clone();
+      let source = fetched.source.as_mut().unwrap();
+      source.etag = Some("\"v1\"".to_owned());
+      source.last_checked_utc = Some(Utc::now());
+      lists.list.update(id, fetched);
+      lists.save()?;
+
+      // Renaming the list doesn't mean fetching it again.
+      let Json(renamed) = put(State(state.clone()), Path(id), extract::Json(list("Betting", "gambling.txt", 60))).await.unwrap();
+      let source = renamed.source.unwrap();
+      assert_eq!(source.etag.as_deref(), Some("\"v1\""));
+      assert!(source.last_checked_utc.is_some());
+
+      // A different source does.
+      let Json(moved) = put(State(state.clone()), Path(id), extract::Json(list("Betting", "casinos.txt", 60))).await.unwrap();
+      let source = moved.source.unwrap();
+      assert_eq!(source.etag, None);
+      assert_eq!(source.last_checked_utc, None);
+
+      Ok(())
+    }
   }
 }
 
 mod netaccess {
   use std::collections::HashMap;
 
-  use crate::{model::NetAccess, unifi::{TrafficRule, TargetDevice}};
+  use crate::{model::{BackendKind, NetAccess}, nftables, unifi::{TrafficRule, TargetDevice}};
 
   use super::*;
   use anyhow::anyhow;
@@ -193,7 +1159,17 @@ mod netaccess {
       .route("/:mac", routing::put(put).route_layer(middleware::from_fn(auth)))
   }
 
+  /// Whether devices' internet access is turned on and off by the nftables backend, rather than by
+  /// traffic rules on a UniFi gateway.
+  fn managed_locally(state: &AppState) -> bool {
+    state.app_config.backends.contains(&BackendKind::Nftables)
+  }
+
   async fn get_all_netaccess(state: AppState) -> anyhow::Result<Vec<NetAccess>> {
+    if managed_locally(&state) {
+      return nftables::load_devices(&state.app_config);
+    }
+
     let mut unifi_client = state.unifi_client.lock().await;
     let unifi_client = unifi_client.as_mut();
     match unifi_client {
@@ -235,7 +1211,7 @@ mod netaccess {
     let all = get_all_netaccess(state).await?;
 
     for access in all {
-      if access.mac_address == mac.clone() {
+      if access.mac_address.eq_ignore_ascii_case(&mac) {
         return Ok(Json(access));
       }
     }
@@ -243,14 +1219,37 @@ mod netaccess {
     Err(MyError::NotFound)
   }
 
-  async fn post(State(state): State<AppState>, extract::Json(access): extract::Json<NetAccess>) -> Result<Json<NetAccess>> {
+  /// Checks the access's MAC address, and lowercases it so the same device is always written the
+  /// same way.
+  fn validate(access: &mut NetAccess) -> Result<()> {
+    if !nftables::is_valid_mac_address(&access.mac_address) {
+      return Err(MyError::BadRequest(format!(
+        "Invalid MAC address '{}'. Use six hex octets like 'aa:bb:cc:dd:ee:ff'",
+        access.mac_address
+      )));
+    }
+    access.mac_address = access.mac_address.to_lowercase();
+    Ok(())
+  }
+
+  async fn post(State(state): State<AppState>, extract::Json(mut access): extract::Json<NetAccess>) -> Result<Json<NetAccess>> {
+    validate(&mut access)?;
     let all = get_all_netaccess(state.clone()).await?;
     for a in all {
-      if a.mac_address == access.mac_address {
+      if a.mac_address.eq_ignore_ascii_case(&access.mac_address) {
         return Err(MyError::BadRequest("Access already exists".to_owned()))
       }
     }
 
+    if managed_locally(&state) {
+      let mut devices = nftables::load_devices(&state.app_config)?;
+      devices.push(access.clone());
+      nftables::save_devices(&state.app_config, &devices)?;
+      state.regenerate().await;
+
+      return Ok(Json(access));
+    }
+
     let block_should_enable = !access.enabled;
     let new_rule = create_block_rule(&access.mac_address, block_should_enable);
 
@@ -277,13 +1276,24 @@ mod netaccess {
     new_rule
   }
 
-  async fn put(State(state): State<AppState>, Path(mac): Path<String>, extract::Json(access): extract::Json<NetAccess>) -> Result<Json<NetAccess>> {
-    if access.mac_address != mac {
+  async fn put(State(state): State<AppState>, Path(mac): Path<String>, extract::Json(mut access): extract::Json<NetAccess>) -> Result<Json<NetAccess>> {
+    if !access.mac_address.eq_ignore_ascii_case(&mac) {
       return Err(MyError::BadRequest("Mac address doesn't match".to_owned()));
     }
+    validate(&mut access)?;
 
     error!("Putting netaccess for {}: {:?}", mac, access);
 
+    if managed_locally(&state) {
+      let mut devices = nftables::load_devices(&state.app_config)?;
+      let device = devices.iter_mut().find(|d| d.mac_address.eq_ignore_ascii_case(&mac)).ok_or(MyError::NotFound)?;
+      *device = access.clone();
+      nftables::save_devices(&state.app_config, &devices)?;
+      state.regenerate().await;
+
+      return Ok(Json(access));
+    }
+
     let mut unifi_client = state.unifi_client.lock().await;
     let unifi_client = unifi_client.as_mut();
     match unifi_client {
@@ -296,7 +1306,7 @@ mod netaccess {
           let block_should_enable = !access.enabled;
           // If this rule has a single target device and it matches, just make sure the enabled flag
           // is correct.
-          if rule.target_devices.len() == 1 && rule.target_devices.first().unwrap().client_mac == mac {
+          if rule.target_devices.len() == 1 && rule.target_devices.first().unwrap().client_mac.eq_ignore_ascii_case(&mac) {
             if rule.enabled != block_should_enable {
               rule.enabled = block_should_enable;
               client.update_traffic_rule(rule).await?;
@@ -312,10 +1322,10 @@ mod netaccess {
             }
 
 
-          } else if let Some(pos) = rule.target_devices.iter().position(|d| d.client_mac == mac) {
+          } else if let Some(pos) = rule.target_devices.iter().position(|d| d.client_mac.eq_ignore_ascii_case(&mac)) {
             if rule.enabled != block_should_enable {
               // We need to pull this mac address out of this rule into its own separate rule. First, create the new rule.
-              let new_rule = create_block_rule(&mac, block_should_enable);
+              let new_rule = create_block_rule(&access.mac_address, block_should_enable);
               client.create_traffic_rule(&new_rule).await?;
 
               // Now remove the target device from its existing rule.
@@ -330,6 +1340,39 @@ mod netaccess {
     }
   }
 
+  #[cfg(test)]
+  mod tests {
+    use super::*;
+    use crate::api::test_state;
+
+    #[tokio::test]
+    async fn lowercases_mac_addresses() -> anyhow::Result<()> {
+      let dir = tempdir::TempDir::new("penguin-api-test")?;
+      let mut state = test_state(&dir);
+      state.app_config.backends = vec![BackendKind::Nftables];
+      std::fs::create_dir(state.app_config.config_path())?;
+      let access = |mac: &str| NetAccess { mac_address: mac.to_owned(), enabled: true, auto_disable_at: None };
+
+      let Json(added) = post(State(state.clone()), extract::Json(access("AA:BB:CC:DD:EE:FF"))).await.unwrap();
+      assert_eq!(added.mac_address, "aa:bb:cc:dd:ee:ff");
+      match post(State(state.clone()), extract::Json(access("aa:bb:cc:dd:ee:ff"))).await {
+        Err(MyError::BadRequest(m)) => assert_eq!(m, "Access already exists"),
+        _ => panic!("Added the same device twice"),
+      }
+
+      let mut disabled = access("Aa:Bb:Cc:Dd:Ee:Ff");
+      disabled.enabled = false;
+      let Json(updated) =
+        put(State(state.clone()), Path("AA:BB:CC:DD:EE:FF".to_owned()), extract::Json(disabled)).await.unwrap();
+      assert_eq!(updated.mac_address, "aa:bb:cc:dd:ee:ff");
+      let devices = nftables::load_devices(&state.app_config)?;
+      assert_eq!(devices.len(), 1);
+      assert_eq!(devices[0].mac_address, "aa:bb:cc:dd:ee:ff");
+      assert!(!devices[0].enabled);
+
+      Ok(())
+    }
+  }
 }
 
 
@@ -357,7 +1400,7 @@ mod logs {
     }
 
     async fn get_all(State(state): State<AppState>, query: Option<Query<LogQuery>>) -> Result<Json<Vec<LogEntry>>> {
-      let mut logs = get_all_logs()?;
+      let mut logs = get_all_logs(&state.app_config.squid_log_dir)?;
 
       if let Some(query) = query {
         if let Some(client_id) = query.client_id {
@@ -380,12 +1423,23 @@ mod logs {
 
 mod proxy {
   use crate::squid::{self, get_status, ActiveState, ServiceStatus};
+  use crate::{rollback_config, GenerationStatus};
   use super::*;
 
   pub fn routes() -> Router<AppState> {
     Router::new()
       .route("/", routing::get(get))
       .route("/", routing::put(put))
+      .route("/config", routing::get(get_config).route_layer(middleware::from_fn(auth)))
+      .route("/config/rollback", routing::post(rollback).route_layer(middleware::from_fn(auth)))
+  }
+
+  async fn get_config(State(state): State<AppState>) -> Result<Json<GenerationStatus>> {
+    Ok(Json(state.gen_config_lock.lock().unwrap().status()))
+  }
+
+  async fn rollback(State(state): State<AppState>) -> Result<Json<GenerationStatus>> {
+    Ok(Json(rollback_config(&state)?))
   }
 
   async fn get() -> Result<Json<ServiceStatus>> {
@@ -407,4 +1461,259 @@ mod proxy {
       }
     }
   }
-}
\ No newline at end of file
+}
+
+mod preview {
+  use std::collections::BTreeSet;
+
+  use serde::{Deserialize, Serialize};
+
+  use crate::backend::{self, enabled_backends, Policy};
+  use crate::list::{Identifiable, IdentifiedList};
+  use crate::model::DomainList;
+  use crate::nftables;
+
+  use super::*;
+
+  pub(super) fn routes() -> Router<AppState> {
+    Router::new()
+      .route("/", routing::post(post).route_layer(middleware::from_fn(auth)))
+  }
+
+  /// Proposed changes to clients and domain lists. Items with an id replace the existing item with
+  /// that id, and items without one are added.
+  #[derive(Deserialize)]
+  struct Changes {
+    #[serde(default)]
+    clients: Vec<Client>,
+    #[serde(default)]
+    removed_clients: Vec<u32>,
+    #[serde(default)]
+    domainlists: Vec<DomainList>,
+    #[serde(default)]
+    removed_domainlists: Vec<u32>,
+  }
+
+  #[derive(Serialize)]
+  struct Preview {
+    /// A unified diff of the configuration the changes would produce against what's in place.
+    diff: String,
+    /// Problems that would stop the changes being saved or the configuration being used.
+    warnings: Vec<String>,
+  }
+
+  fn message(error: MyError) -> String {
+    match error {
+      MyError::BadRequest(m) => m,
+      MyError::BadRequestDetails(m, details) => format!("{}: {}", m, details),
+      MyError::Failed(e) => e.to_string(),
+      MyError::NotFound => "Not found".to_owned(),
+      MyError::NotAuthorized => "Not authorized".to_owned(),
+    }
+  }
+
+  /// Replaces the item with the same id, or adds the item if it doesn't have one. Returns the id
+  /// the item ends up with, or None if there's nothing to replace.
+  fn upsert<T: Identifiable + Clone>(list: &mut IdentifiedList<T>, item: T) -> Option<u32> {
+    match item.id() {
+      Some(id) => list.update(id, item).map(|_| id),
+      None => list.add(item).id(),
+    }
+  }
+
+  /// Shows what the configuration would look like after some changes, without saving them.
+  async fn post(State(state): State<AppState>, extract::Json(changes): extract::Json<Changes>) -> Result<Json<Preview>> {
+    let mut warnings = Vec::new();
+
+    let mut domains = domains::load(&state)?;
+    for id in changes.removed_domainlists.iter() {
+      domains.list.delete(*id);
+    }
+    let mut changed_lists = Vec::new();
+    for mut list in changes.domainlists {
+      let stored = domains.list.items.iter().find(|l| list.id.is_some() && l.id == list.id);
+      if let Err(e) = domains::normalize(&state, &mut list, stored) {
+        warnings.push(format!("Domain list '{}': {}", list.name, message(e)));
+      }
+      match upsert(&mut domains.list, list.clone()) {
+        Some(id) => changed_lists.push((id, list)),
+        None => warnings.push(format!("Domain list '{}' doesn't exist", list.name)),
+      }
+    }
+    for (id, list) in changed_lists.iter() {
+      if let Err(e) = domains::check_includes(&domains.list, Some(*id), list) {
+        warnings.push(format!("Domain list '{}': {}", list.name, message(e)));
+      }
+    }
+
+    let mut clients = clients::load(&state)?;
+    for id in changes.removed_clients.iter() {
+      clients.list.delete(*id);
+    }
+    let mut changed_clients = Vec::new();
+    for mut client in changes.clients {
+      client.assign_lease_ids();
+      match upsert(&mut clients.list, client.clone()) {
+        Some(id) => changed_clients.push(id),
+        None => warnings.push(format!("Client '{}' doesn't exist", client.name)),
+      }
+    }
+    for id in changed_clients {
+      let mut client = clients.list.items.iter().find(|c| c.id == Some(id)).unwrap().clone();
+      match clients::validate(&clients, &mut client) {
+        Ok(()) => {
+          clients.list.update(id, client);
+        }
+        Err(e) => warnings.push(format!("Client '{}': {}", client.name, message(e))),
+      }
+    }
+
+    // Removing a list that's still used would leave rules referring to an acl that doesn't exist.
+    let global_leases = global_leases::load(&state)?;
+    let exists = |id: &u32| domains.list.items.iter().any(|l| l.id == Some(*id));
+    for client in clients.list.items.iter() {
+      for id in domains::client_lists(client).iter().filter(|id| !exists(id)) {
+        warnings.push(format!("Client '{}' uses domain list {}, which doesn't exist", client.name, id));
+      }
+    }
+    for lease in global_leases.list.items.iter() {
+      let missing: BTreeSet<_> = domains::rule_lists(&lease.rule).filter(|id| !exists(id)).collect();
+      for id in missing {
+        warnings.push(format!("Global lease {} uses domain list {}, which doesn't exist", lease.id.unwrap(), id));
+      }
+    }
+
+    let exhausted_quotas = state.gen_config_lock.lock().unwrap().exhausted_quotas.clone();
+    let devices = nftables::load_devices(&state.app_config)?;
+    let policy = Policy {
+      clients: &clients.list,
+      domainlists: &domains.list,
+      global_leases: &global_leases.list,
+      exhausted_quotas: &exhausted_quotas,
+      devices: &devices,
+    };
+
+    let mut diff = String::new();
+    for backend in enabled_backends(&state.app_config) {
+      match backend::preview(backend.as_ref(), &policy) {
+        Ok(preview) => {
+          diff.push_str(&preview.diff);
+          if let Some(rejection) = preview.rejection {
+            warnings.push(format!("{}: {}", backend.name(), rejection));
+          }
+        }
+        Err(e) => warnings.push(format!("{}: {}", backend.name(), e)),
+      }
+    }
+
+    Ok(Json(Preview { diff, warnings }))
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::api::test_state;
+    use crate::generate::write_config_files;
+
+    /// The contents of every file under a directory.
+    fn snapshot(dir: &std::path::Path) -> BTreeMap<PathBuf, Vec<u8>> {
+      let mut files = BTreeMap::new();
+      for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+          files.extend(snapshot(&path));
+        } else {
+          files.insert(path.clone(), std::fs::read(&path).unwrap());
+        }
+      }
+      files
+    }
+
+    #[tokio::test]
+    async fn previews_without_saving() -> anyhow::Result<()> {
+      let dir = tempdir::TempDir::new("penguin-api-test")?;
+      let state = test_state(&dir);
+      std::fs::create_dir(state.app_config.config_path())?;
+      let squid_dir = PathBuf::from(&state.app_config.squid_config_dir);
+      std::fs::create_dir(&squid_dir)?;
+
+      let mut domains = domains::load(&state)?;
+      for (name, domain) in [
+        ("Horrible websites", "horribleweb.com"),
+        ("Games", "games.example.com"),
+        ("Music", "music.example.com"),
+      ] {
+        domains.list.add(serde_json::from_value(serde_json::json!({ "name": name, "domains": [domain] }))?);
+      }
+      domains.save()?;
+      let mut clients = clients::load(&state)?;
+      clients.list.add(serde_json::from_value(serde_json::json!({
+        "ip": "192.168.1.33",
+        "name": "Laptop",
+        "rules": [{ "kind": "deny_http_access", "domainlists": [1] }],
+        "leases": [{ "end_date_utc": null, "rule": { "kind": "deny_http_access", "domainlists": [2] } }]
+      }))?);
+      clients.list.add(serde_json::from_value(serde_json::json!({
+        "ip": "192.168.1.35",
+        "name": "Tablet",
+        "bandwidth": { "rate_kbps": 1000, "domainlists": [2] }
+      }))?);
+      clients.save()?;
+      let mut global_leases = global_leases::load(&state)?;
+      global_leases.list.add(serde_json::from_value(serde_json::json!({
+        "end_date_utc": null,
+        "rule": { "kind": "deny_http_access", "domainlists": [2] }
+      }))?);
+      global_leases.save()?;
+
+      // Put the current configuration in place, along with things penguin didn't write.
+      let policy = Policy {
+        clients: &clients.list,
+        domainlists: &domains.list,
+        global_leases: &global_leases.list,
+        exhausted_quotas: &Default::default(),
+        devices: &[],
+      };
+      for backend in enabled_backends(&state.app_config) {
+        let dest = std::fs::canonicalize(backend.install_dir())?;
+        write_config_files(&dest, &backend.generate(&dest, &policy)?)?;
+      }
+      std::fs::create_dir(squid_dir.join("backup"))?;
+      std::fs::write(squid_dir.join("core"), [0xff, 0xfe, 0x00])?;
+      let before = snapshot(dir.path());
+
+      let changes: Changes = serde_json::from_value(serde_json::json!({
+        "domainlists": [
+          { "id": 1, "name": "Horrible websites", "domains": ["horribleweb.com", "https://WorseWeb.com/page"] },
+          { "name": "Videos", "domains": ["videos.example.com"] },
+          { "id": 9, "name": "Missing", "domains": [] }
+        ],
+        "removed_domainlists": [2],
+        "clients": [{ "id": 5, "ip": "192.168.1.34", "name": "Phone", "rules": [], "leases": [] }]
+      }))?;
+      let Json(preview) = post(State(state.clone()), extract::Json(changes)).await.unwrap();
+
+      assert_eq!(
+        preview.warnings,
+        vec![
+          "Domain list 'Missing' doesn't exist",
+          "Client 'Phone' doesn't exist",
+          "Client 'Laptop' uses domain list 2, which doesn't exist",
+          "Client 'Tablet' uses domain list 2, which doesn't exist",
+          "Global lease 1 uses domain list 2, which doesn't exist",
+        ]
+      );
+      assert!(preview.diff.contains("+++ b/squid/domains_0001.txt\n"));
+      assert!(preview.diff.contains("\n+worseweb.com\n"));
+      assert!(preview.diff.contains("+++ b/squid/domains_0004.txt\n"));
+      assert!(preview.diff.contains("--- a/squid/domains_0002.txt\n+++ /dev/null\n"));
+      assert!(!preview.diff.contains("core"));
+      assert_eq!(snapshot(dir.path()), before);
+
+      Ok(())
+    }
+  }
+}
@@ -5,6 +5,7 @@ use anyhow::anyhow;
 use crate::{errors::MyError, model::Conf};
 
 
+#[derive(Clone)]
 pub struct AuthedUser {
   pub email: String
 }
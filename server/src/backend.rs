@@ -0,0 +1,419 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use tempdir::TempDir;
+
+use crate::file::get_parent_or_die;
+use crate::generate::{
+  diff_config_files, generate_squid_config, hash_config_dir, hash_config_files, read_config_dir,
+  write_config_files, ConfigFiles, ConfigHashes,
+};
+use crate::list::IdentifiedList;
+use crate::model::{BackendKind, Client, Conf, DomainList, Lease, NetAccess};
+use crate::quota::ExhaustedQuotas;
+use crate::{blockpage, nftables, squid, unbound};
+
+/// Everything a backend needs to know to enforce the rules.
+pub struct Policy<'a> {
+  pub clients: &'a IdentifiedList<Client>,
+  pub domainlists: &'a IdentifiedList<DomainList>,
+  pub global_leases: &'a IdentifiedList<Lease>,
+  pub exhausted_quotas: &'a ExhaustedQuotas,
+  /// Devices whose internet access is turned on and off locally.
+  pub devices: &'a [NetAccess],
+}
+
+/// Something that enforces clients' rules and leases, by generating configuration for a daemon.
+pub trait EnforcementBackend {
+  /// A short name for the backend, used in logs and the API.
+  fn name(&self) -> &'static str;
+
+  /// The directory the backend's configuration is put in place in.
+  fn install_dir(&self) -> &str;
+
+  /// Generates configuration files. Files that refer to each other do so by their paths in `dir`.
+  fn generate(&self, dir: &Path, policy: &Policy) -> Result<ConfigFiles>;
+
+  /// Whether generated configuration should be checked before it's put in place.
+  fn validates(&self) -> bool;
+
+  /// Checks that the daemon accepts the configuration in `dir`.
+  fn check(&self, dir: &Path) -> Result<()>;
+
+  /// Makes the daemon pick up new configuration, if the backend is set up to.
+  fn reload(&self);
+
+  /// Whether the daemon keeps its configuration across restarts of the machine. Configuration that
+  /// isn't kept has to be loaded when penguin starts, even if the files haven't changed.
+  fn persistent(&self) -> bool {
+    true
+  }
+}
+
+pub struct SquidBackend {
+  conf: Conf,
+}
+
+impl EnforcementBackend for SquidBackend {
+  fn name(&self) -> &'static str {
+    "squid"
+  }
+
+  fn install_dir(&self) -> &str {
+    &self.conf.squid_config_dir
+  }
+
+  fn generate(&self, dir: &Path, policy: &Policy) -> Result<ConfigFiles> {
+    generate_squid_config(
+      dir,
+      policy.clients,
+      policy.domainlists,
+      policy.global_leases,
+      policy.exhausted_quotas,
+      self
+        .conf
+        .block_page
+        .enabled
+        .then_some(self.conf.block_page.url.as_str()),
+    )
+  }
+
+  fn validates(&self) -> bool {
+    self.conf.validate_squid_config
+  }
+
+  fn check(&self, dir: &Path) -> Result<()> {
+    squid::check_config(&self.conf.squid_binary, dir)
+  }
+
+  fn reload(&self) {
+    if self.conf.hup_squid_daemon {
+      squid::reload_config();
+    }
+  }
+}
+
+pub struct UnboundBackend {
+  conf: Conf,
+}
+
+impl EnforcementBackend for UnboundBackend {
+  fn name(&self) -> &'static str {
+    "unbound"
+  }
+
+  fn install_dir(&self) -> &str {
+    &self.conf.unbound.config_dir
+  }
+
+  fn generate(&self, _dir: &Path, policy: &Policy) -> Result<ConfigFiles> {
+    let block_page_host = if self.conf.block_page.enabled {
+      Some(blockpage::host_and_port(&self.conf.block_page.url)?.0)
+    } else {
+      None
+    };
+    unbound::generate_unbound_config(
+      policy.clients,
+      policy.domainlists,
+      policy.global_leases,
+      policy.exhausted_quotas,
+      block_page_host.as_deref(),
+    )
+  }
+
+  fn validates(&self) -> bool {
+    self.conf.unbound.validate_config
+  }
+
+  fn check(&self, dir: &Path) -> Result<()> {
+    unbound::check_config(&self.conf.unbound.checkconf_binary, dir)
+  }
+
+  fn reload(&self) {
+    if self.conf.unbound.reload_daemon {
+      unbound::reload_config();
+    }
+  }
+}
+
+pub struct NftablesBackend {
+  conf: Conf,
+}
+
+impl EnforcementBackend for NftablesBackend {
+  fn name(&self) -> &'static str {
+    "nftables"
+  }
+
+  fn install_dir(&self) -> &str {
+    &self.conf.nftables.config_dir
+  }
+
+  fn generate(&self, _dir: &Path, policy: &Policy) -> Result<ConfigFiles> {
+    nftables::generate_nftables_config(policy.clients, policy.devices)
+  }
+
+  fn validates(&self) -> bool {
+    self.conf.nftables.validate_config
+  }
+
+  fn check(&self, dir: &Path) -> Result<()> {
+    nftables::check_config(&self.conf.nftables.nft_binary, dir)
+  }
+
+  // The ruleset lives in the kernel, so it's gone after a reboot or an `nft flush ruleset`.
+  fn persistent(&self) -> bool {
+    false
+  }
+
+  fn reload(&self) {
+    if self.conf.nftables.load_ruleset {
+      let dir = Path::new(&self.conf.nftables.config_dir);
+      if let Err(e) = nftables::load_ruleset(&self.conf.nftables.nft_binary, dir) {
+        tracing::error!("Failed to load nftables ruleset: {:?}", e);
+      }
+    }
+  }
+}
+
+/// The backends that are turned on in the configuration.
+pub fn enabled_backends(conf: &Conf) -> Vec<Box<dyn EnforcementBackend + Send>> {
+  let mut backends: Vec<Box<dyn EnforcementBackend + Send>> = Vec::new();
+  for kind in conf.backends.iter() {
+    match kind {
+      BackendKind::Squid => backends.push(Box::new(SquidBackend { conf: conf.clone() })),
+      BackendKind::Unbound => backends.push(Box::new(UnboundBackend { conf: conf.clone() })),
+      BackendKind::Nftables => backends.push(Box::new(NftablesBackend { conf: conf.clone() })),
+    }
+  }
+  backends
+}
+
+fn dest_dir(backend: &dyn EnforcementBackend) -> Result<PathBuf> {
+  std::fs::create_dir_all(backend.install_dir())?;
+  Ok(std::fs::canonicalize(backend.install_dir())?)
+}
+
+fn old_dir(backend: &dyn EnforcementBackend, dest_dir: &Path) -> Result<PathBuf> {
+  Ok(get_parent_or_die(dest_dir)?.join(format!("{}_old", backend.name())))
+}
+
+/// Generates a backend's configuration and puts it in place, unless it's the same as what's already
+/// there. Returns the hashes of the new files if they were put in place.
+pub fn apply(backend: &dyn EnforcementBackend, policy: &Policy) -> Result<Option<ConfigHashes>> {
+  let dest_dir = dest_dir(backend)?;
+
+  // Reloading drops connections that are in progress, so leave the daemon alone if nothing changed.
+  let files = backend.generate(&dest_dir, policy)?;
+  let hashes = hash_config_files(&files);
+  if hashes == hash_config_dir(&dest_dir)? {
+    return Ok(None);
+  }
+
+  // Configuration the daemon can't parse would take down the whole network, so keep the previous
+  // one until the problem is fixed.
+  if backend.validates() {
+    check(backend, &dest_dir, &files)?;
+  }
+
+  let temp_dir = TempDir::new("penguin-generated")?;
+  write_config_files(temp_dir.path(), &files)?;
+  tracing::info!("Writing {} config to {:?}", backend.name(), dest_dir);
+  install_config(
+    &temp_dir.into_path(),
+    &dest_dir,
+    &old_dir(backend, &dest_dir)?,
+  )?;
+  backend.reload();
+
+  Ok(Some(hashes))
+}
+
+/// Checks that the daemon accepts configuration generated for `dest_dir`. The files refer to each
+/// other by where they'll end up, so they're checked in a copy that refers to its own files instead.
+fn check(backend: &dyn EnforcementBackend, dest_dir: &Path, files: &ConfigFiles) -> Result<()> {
+  let check_dir = TempDir::new("penguin-check")?;
+  let from = format!("{}/", dest_dir.display());
+  let to = format!("{}/", check_dir.path().display());
+  let files: ConfigFiles = files
+    .iter()
+    .map(|(name, content)| (name.clone(), content.replace(&from, &to)))
+    .collect();
+  write_config_files(check_dir.path(), &files)?;
+  backend.check(check_dir.path())
+}
+
+/// What applying a policy would do to a backend's configuration.
+pub struct Preview {
+  /// A unified diff against the configuration that's in place.
+  pub diff: String,
+  /// Why the daemon would reject the configuration, if it's checked and would be.
+  pub rejection: Option<String>,
+}
+
+/// Generates a backend's configuration and compares it with what's in place, without changing
+/// anything.
+pub fn preview(backend: &dyn EnforcementBackend, policy: &Policy) -> Result<Preview> {
+  let install_dir = Path::new(backend.install_dir());
+  let dest_dir = std::fs::canonicalize(install_dir).unwrap_or_else(|_| install_dir.to_owned());
+  let files = backend.generate(&dest_dir, policy)?;
+  let diff = diff_config_files(backend.name(), &read_config_dir(&dest_dir)?, &files);
+
+  let rejection = match backend.validates() && !diff.is_empty() {
+    true => check(backend, &dest_dir, &files)
+      .err()
+      .map(|e| e.to_string()),
+    false => None,
+  };
+
+  Ok(Preview { diff, rejection })
+}
+
+/// Makes the daemon load the configuration that's already in place. Returns the hashes of its files.
+pub fn reload_installed(backend: &dyn EnforcementBackend) -> Result<ConfigHashes> {
+  let dest_dir = dest_dir(backend)?;
+  tracing::info!("Loading {} config from {:?}", backend.name(), dest_dir);
+  backend.reload();
+  hash_config_dir(&dest_dir)
+}
+
+/// Puts the previous configuration of a backend back in place, and makes the daemon reload it.
+/// Returns the hashes of the restored files.
+pub fn rollback(backend: &dyn EnforcementBackend) -> Result<ConfigHashes> {
+  let dest_dir = dest_dir(backend)?;
+  rollback_config(&dest_dir, &old_dir(backend, &dest_dir)?)?;
+  tracing::info!("Rolled back {} config in {:?}", backend.name(), dest_dir);
+  backend.reload();
+  hash_config_dir(&dest_dir)
+}
+
+/// Puts a generated configuration directory in place, keeping the previous one in `old_dir`.
+fn install_config(generated: &Path, dest_dir: &Path, old_dir: &Path) -> Result<()> {
+  if old_dir.exists() {
+    std::fs::remove_dir_all(old_dir)?;
+  }
+  if dest_dir.exists() {
+    std::fs::rename(dest_dir, old_dir)?;
+  }
+  std::fs::rename(generated, dest_dir)?;
+  Ok(())
+}
+
+/// Swaps the current configuration directory with the previous one, so rolling back twice gets
+/// back to where it started.
+fn rollback_config(dest_dir: &Path, old_dir: &Path) -> Result<()> {
+  if !old_dir.exists() {
+    return Err(anyhow!("There's no previous configuration to roll back to"));
+  }
+
+  let swap_dir = get_parent_or_die(dest_dir)?.join("penguin_swap");
+  if swap_dir.exists() {
+    std::fs::remove_dir_all(&swap_dir)?;
+  }
+  std::fs::rename(dest_dir, &swap_dir)?;
+  std::fs::rename(old_dir, dest_dir)?;
+  std::fs::rename(&swap_dir, old_dir)?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::Cell;
+
+  use super::*;
+
+  /// A backend whose main file includes a second one, and which counts how often it generates.
+  struct IncludingBackend {
+    install_dir: String,
+    generated: Cell<u32>,
+  }
+
+  impl EnforcementBackend for IncludingBackend {
+    fn name(&self) -> &'static str {
+      "including"
+    }
+
+    fn install_dir(&self) -> &str {
+      &self.install_dir
+    }
+
+    fn generate(&self, dir: &Path, _policy: &Policy) -> Result<ConfigFiles> {
+      self.generated.set(self.generated.get() + 1);
+      Ok(ConfigFiles::from([
+        (
+          "main.conf".to_owned(),
+          format!("include {}\n", dir.join("list.txt").display()),
+        ),
+        ("list.txt".to_owned(), "example.com\n".to_owned()),
+      ]))
+    }
+
+    fn validates(&self) -> bool {
+      true
+    }
+
+    fn check(&self, dir: &Path) -> Result<()> {
+      let main = std::fs::read_to_string(dir.join("main.conf"))?;
+      let include = main.trim().strip_prefix("include ").unwrap();
+      std::fs::read_to_string(include)?;
+      assert!(Path::new(include).starts_with(dir));
+      Ok(())
+    }
+
+    fn reload(&self) {}
+  }
+
+  #[test]
+  fn installs_and_rolls_back() -> Result<()> {
+    let dir = TempDir::new("penguin-backend-test")?;
+    let dest = dir.path().join("squid.d");
+    let old = dir.path().join("squid_old");
+    let generate = |content: &str| -> Result<PathBuf> {
+      let generated = TempDir::new_in(dir.path(), "generated")?.into_path();
+      std::fs::write(generated.join("domains.conf"), content)?;
+      Ok(generated)
+    };
+    let current = || std::fs::read_to_string(dest.join("domains.conf")).unwrap();
+
+    assert!(rollback_config(&dest, &old).is_err());
+    install_config(&generate("first")?, &dest, &old)?;
+    install_config(&generate("second")?, &dest, &old)?;
+    assert_eq!(current(), "second");
+
+    rollback_config(&dest, &old)?;
+    assert_eq!(current(), "first");
+    rollback_config(&dest, &old)?;
+    assert_eq!(current(), "second");
+
+    Ok(())
+  }
+
+  #[test]
+  fn checks_in_a_copy() -> Result<()> {
+    let dir = TempDir::new("penguin-backend-test")?;
+    let backend = IncludingBackend {
+      install_dir: dir.path().join("including.d").display().to_string(),
+      generated: Cell::new(0),
+    };
+    let policy = Policy {
+      clients: &IdentifiedList::new(vec![]),
+      domainlists: &IdentifiedList::new(vec![]),
+      global_leases: &IdentifiedList::new(vec![]),
+      exhausted_quotas: &ExhaustedQuotas::new(),
+      devices: &[],
+    };
+
+    let preview = preview(&backend, &policy)?;
+    assert_eq!(preview.rejection, None);
+    assert!(apply(&backend, &policy)?.is_some());
+    assert_eq!(backend.generated.get(), 2);
+
+    let dest = std::fs::canonicalize(&backend.install_dir)?;
+    assert_eq!(
+      std::fs::read_to_string(dest.join("main.conf"))?,
+      format!("include {}\n", dest.join("list.txt").display())
+    );
+
+    Ok(())
+  }
+}
@@ -0,0 +1,479 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local, Utc};
+use serde::Deserialize;
+
+use crate::list::IdentifiedList;
+use crate::model::{BlockPageConfig, Client, DomainList, Lease, Rule, RuleKind};
+use crate::quota::ExhaustedQuotas;
+use crate::schedule::is_active;
+
+/// The block page used when the configuration doesn't name a template of its own.
+pub const DEFAULT_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Blocked</title>
+<style>
+  body { font-family: sans-serif; max-width: 40em; margin: 3em auto; padding: 0 1em; color: #333; }
+  .url { word-break: break-all; color: #666; }
+</style>
+</head>
+<body>
+<h1>This page is blocked</h1>
+<p class="url">{{url}}</p>
+<p>{{client}} can't visit it because of {{rule}}, which blocks {{list}}.</p>
+<p><a href="{{request_access_url}}">Ask for access</a></p>
+</body>
+</html>
+"#;
+
+/// What squid tells the block page about a request it denied.
+#[derive(Deserialize)]
+pub struct Blocked {
+  /// The domain list that denied the request, if it was a list.
+  pub list: Option<u32>,
+  /// The address of the client that made the request.
+  pub ip: Option<String>,
+  pub url: Option<String>,
+}
+
+/// Why a request was blocked, in words.
+#[derive(Debug, PartialEq)]
+pub struct Reason {
+  pub client: String,
+  pub list: String,
+  pub rule: String,
+  pub url: String,
+  /// When the lease that blocked the request ends, if it was a lease.
+  pub lease_ends: Option<DateTime<Utc>>,
+}
+
+/// The host and port squid redirects denied requests to, which clients that are denied everything
+/// still need to be able to reach.
+pub fn host_and_port(url: &str) -> Result<(String, u16)> {
+  let parsed =
+    reqwest::Url::parse(url).with_context(|| format!("Invalid block page url {}", url))?;
+  let host = parsed
+    .host_str()
+    .ok_or_else(|| anyhow!("The block page url {} has no host", url))?;
+  let port = parsed
+    .port_or_known_default()
+    .ok_or_else(|| anyhow!("The block page url {} has no port", url))?;
+  Ok((
+    host
+      .trim_start_matches('[')
+      .trim_end_matches(']')
+      .to_owned(),
+    port,
+  ))
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+  time.with_timezone(&Local).format("%a %H:%M").to_string()
+}
+
+/// Works out which rule or lease denied a client a request, out of those squid is enforcing. Squid
+/// checks deny leases before rules, so they're looked at first too.
+pub fn reason(
+  blocked: &Blocked,
+  clients: &IdentifiedList<Client>,
+  domainlists: &IdentifiedList<DomainList>,
+  global_leases: &IdentifiedList<Lease>,
+  exhausted_quotas: &ExhaustedQuotas,
+  now: DateTime<Utc>,
+) -> Reason {
+  let client = blocked
+    .ip
+    .as_ref()
+    .and_then(|ip| clients.items.iter().find(|c| &c.ip == ip));
+  let list = blocked
+    .list
+    .and_then(|id| domainlists.items.iter().find(|l| l.id == Some(id)));
+
+  let mut reason = Reason {
+    client: client.map_or("This device".to_owned(), |c| c.name.clone()),
+    list: match (blocked.list, list) {
+      (_, Some(l)) => format!("'{}'", l.name),
+      (Some(_), None) => "a blocked list".to_owned(),
+      (None, None) => "this site".to_owned(),
+    },
+    rule: "a rule".to_owned(),
+    url: blocked.url.clone().unwrap_or_default(),
+    lease_ends: None,
+  };
+  let Some(client) = client else {
+    return reason;
+  };
+
+  let lease = client
+    .leases
+    .iter()
+    .chain(global_leases.items.iter())
+    .filter(|l| l.rule.kind == RuleKind::DenyHttpAccess && l.is_active(now))
+    .find(|l| match blocked.list {
+      Some(id) => l.rule.domainlists.contains(&id),
+      // Only leases that deny everything, or list domains of their own, deny without a list.
+      None => l.rule.domainlists.is_empty(),
+    });
+  if let Some(lease) = lease {
+    let ends = now + lease.remaining(now);
+    reason.rule = format!("a lease until {}", format_time(ends));
+    reason.lease_ends = Some(ends);
+    return reason;
+  }
+
+  let scheduled = |r: &Rule| {
+    r.schedule
+      .as_ref()
+      .is_none_or(|s| is_active(s, now).unwrap_or(false))
+  };
+  let rule = client
+    .rules
+    .iter()
+    .enumerate()
+    .filter(|(_, r)| scheduled(r))
+    .find(|(index, r)| match (r.kind, blocked.list) {
+      (RuleKind::DenyHttpAccess, Some(id)) => r.domainlists.contains(&id),
+      (RuleKind::QuotaHttpAccess, Some(id)) => {
+        r.domainlists.contains(&id) && exhausted_quotas.contains(&(client.id.unwrap(), *index))
+      }
+      // Without a list, the request was denied for its content type or file extension, or
+      // because an allow rule only allows its own lists.
+      (RuleKind::DenyHttpAccess, None) => !r.mime_types.is_empty() || !r.file_extensions.is_empty(),
+      (RuleKind::AllowHttpAccess, None) => true,
+      _ => false,
+    });
+  reason.rule = match rule {
+    Some((index, r)) if r.kind == RuleKind::QuotaHttpAccess => {
+      format!("rule {}, whose daily quota is used up", index + 1)
+    }
+    Some((index, r)) if r.kind == RuleKind::AllowHttpAccess => {
+      format!("rule {}, which only allows some sites", index + 1)
+    }
+    Some((index, _)) => format!("rule {}", index + 1),
+    None => reason.rule,
+  };
+  reason
+}
+
+fn escape_html(s: &str) -> String {
+  s.replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+    .replace('\'', "&#39;")
+}
+
+fn encode_url_component(s: &str) -> String {
+  s.bytes()
+    .map(|b| match b {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+        (b as char).to_string()
+      }
+      _ => format!("%{:02X}", b),
+    })
+    .collect()
+}
+
+/// The value of the `{{client}}`, `{{list}}`, `{{rule}}`, `{{url}}` or `{{lease_ends}}` placeholder.
+fn placeholder(reason: &Reason, name: &str) -> Option<String> {
+  match name {
+    "client" => Some(reason.client.clone()),
+    "list" => Some(reason.list.clone()),
+    "rule" => Some(reason.rule.clone()),
+    "url" => Some(reason.url.clone()),
+    "lease_ends" => Some(reason.lease_ends.map(format_time).unwrap_or_default()),
+    _ => None,
+  }
+}
+
+/// Replaces each `{{name}}` in a template with `value(name)`, leaving placeholders it doesn't know
+/// alone. Everything is replaced in one pass, so placeholders within values (which can come from the
+/// url) aren't filled in.
+fn fill<F: Fn(&str) -> Option<String>>(template: &str, value: F) -> String {
+  let mut filled = String::with_capacity(template.len());
+  let mut rest = template;
+  while let Some(start) = rest.find("{{") {
+    filled.push_str(&rest[..start]);
+    let after = &rest[start + 2..];
+    let replaced = after
+      .find("}}")
+      .and_then(|end| Some((end, value(&after[..end])?)));
+    match replaced {
+      Some((end, value)) => {
+        filled.push_str(&value);
+        rest = &after[end + 2..];
+      }
+      None => {
+        filled.push_str("{{");
+        rest = after;
+      }
+    }
+  }
+  filled.push_str(rest);
+  filled
+}
+
+/// Renders the block page for a reason. The template can also use `{{request_access_url}}`, the
+/// link for asking for access.
+pub fn render(template: &str, request_access_url: &str, reason: &Reason) -> String {
+  let request_access_url = fill(request_access_url, |name| {
+    placeholder(reason, name).map(|v| encode_url_component(&v))
+  });
+  fill(template, |name| match name {
+    "request_access_url" => Some(escape_html(&request_access_url)),
+    _ => placeholder(reason, name).map(|v| escape_html(&v)),
+  })
+}
+
+/// The template set in the configuration, read afresh so that changes show up straight away.
+pub fn load_template(conf: &BlockPageConfig) -> Result<String> {
+  match &conf.template {
+    Some(path) => Ok(std::fs::read_to_string(path)?),
+    None => Ok(DEFAULT_TEMPLATE.to_owned()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::model::{Day, ListKind, Schedule, TimeRange};
+  use chrono::{Datelike, Duration};
+
+  fn rule(kind: RuleKind, domainlists: Vec<u32>) -> Rule {
+    Rule {
+      kind,
+      domainlists,
+      domains: vec![],
+      exception_domains: vec![],
+      exception_domainlists: vec![],
+      mime_types: vec![],
+      file_extensions: vec![],
+      schedule: None,
+      daily_quota_minutes: None,
+    }
+  }
+
+  fn client(rules: Vec<Rule>, leases: Vec<Lease>) -> IdentifiedList<Client> {
+    IdentifiedList::new(vec![Client {
+      id: Some(1),
+      ip: "192.168.1.33".to_owned(),
+      name: "Laptop".to_owned(),
+      rules,
+      leases,
+      mac_address: None,
+      bandwidth: None,
+      safe_search: false,
+    }])
+  }
+
+  fn domainlists() -> IdentifiedList<DomainList> {
+    IdentifiedList::new(
+      [(1, "Games"), (2, "Videos")]
+        .into_iter()
+        .map(|(id, name)| DomainList {
+          id: Some(id),
+          name: name.to_owned(),
+          kind: ListKind::Dstdomain,
+          domains: vec![],
+          includes: vec![],
+          source: None,
+        })
+        .collect(),
+    )
+  }
+
+  fn blocked(list: u32) -> Blocked {
+    Blocked {
+      list: Some(list),
+      ip: Some("192.168.1.33".to_owned()),
+      url: Some("http://games.example.com/play?level=2".to_owned()),
+    }
+  }
+
+  #[allow(deprecated)]
+  fn deny_lease(domainlists: Vec<u32>, end: DateTime<Utc>) -> Lease {
+    Lease {
+      id: Some(1),
+      start_date_utc: None,
+      end_date_utc: Some(end),
+      end_date: None,
+      recurrence: None,
+      rule: rule(RuleKind::DenyHttpAccess, domainlists),
+      granted_by: None,
+      created_date_utc: None,
+    }
+  }
+
+  /// A schedule for every day but today.
+  fn not_today(now: DateTime<Utc>) -> Schedule {
+    let today = now.weekday().num_days_from_sunday();
+    Schedule {
+      days: [
+        Day::Sun,
+        Day::Mon,
+        Day::Tue,
+        Day::Wed,
+        Day::Thu,
+        Day::Fri,
+        Day::Sat,
+      ]
+      .into_iter()
+      .filter(|d| *d as u32 != today)
+      .collect(),
+      times: vec![TimeRange {
+        start: "00:00".to_owned(),
+        end: "00:00".to_owned(),
+      }],
+      timezone: Some("UTC".to_owned()),
+    }
+  }
+
+  #[test]
+  fn reasons() {
+    let now = Utc::now();
+    let mut scheduled = rule(RuleKind::DenyHttpAccess, vec![1]);
+    scheduled.schedule = Some(not_today(now));
+    let clients = client(
+      vec![
+        rule(RuleKind::DenyHttpAccess, vec![2]),
+        scheduled,
+        rule(RuleKind::QuotaHttpAccess, vec![1]),
+      ],
+      vec![],
+    );
+    let exhausted = ExhaustedQuotas::from([(1, 2)]);
+    let reason = reason(
+      &blocked(1),
+      &clients,
+      &domainlists(),
+      &IdentifiedList::new(vec![]),
+      &exhausted,
+      now,
+    );
+    assert_eq!(
+      reason,
+      Reason {
+        client: "Laptop".to_owned(),
+        list: "'Games'".to_owned(),
+        rule: "rule 3, whose daily quota is used up".to_owned(),
+        url: "http://games.example.com/play?level=2".to_owned(),
+        lease_ends: None,
+      }
+    );
+
+    // Neither the scheduled rule nor the quota rule is being enforced.
+    let reason = super::reason(
+      &blocked(1),
+      &clients,
+      &domainlists(),
+      &IdentifiedList::new(vec![]),
+      &ExhaustedQuotas::new(),
+      now,
+    );
+    assert_eq!(reason.rule, "a rule");
+
+    let end = now + Duration::hours(2);
+    let global_leases = IdentifiedList::new(vec![deny_lease(vec![2], end)]);
+    let reason = super::reason(
+      &blocked(2),
+      &clients,
+      &domainlists(),
+      &global_leases,
+      &exhausted,
+      now,
+    );
+    assert_eq!(reason.rule, format!("a lease until {}", format_time(end)));
+    assert_eq!(reason.lease_ends, Some(end));
+
+    // Requests denied without a list.
+    let mut downloads = rule(RuleKind::DenyHttpAccess, vec![]);
+    downloads.file_extensions = vec!["exe".to_owned()];
+    let unlisted = Blocked {
+      list: None,
+      ..blocked(1)
+    };
+    let reason = |clients: &IdentifiedList<Client>, global_leases: Vec<Lease>| {
+      let reason = super::reason(
+        &unlisted,
+        clients,
+        &domainlists(),
+        &IdentifiedList::new(global_leases),
+        &exhausted,
+        now,
+      );
+      (reason.list, reason.rule)
+    };
+    let clients = client(
+      vec![downloads, rule(RuleKind::AllowHttpAccess, vec![1])],
+      vec![],
+    );
+    assert_eq!(
+      reason(&clients, vec![]),
+      ("this site".to_owned(), "rule 1".to_owned())
+    );
+    assert_eq!(
+      reason(&clients, vec![deny_lease(vec![], end)]).1,
+      format!("a lease until {}", format_time(end))
+    );
+    let clients = client(vec![rule(RuleKind::AllowHttpAccess, vec![1])], vec![]);
+    assert_eq!(
+      reason(&clients, vec![]).1,
+      "rule 1, which only allows some sites"
+    );
+
+    let unknown = Blocked {
+      list: Some(7),
+      ip: Some("192.168.1.99".to_owned()),
+      url: None,
+    };
+    let reason = super::reason(
+      &unknown,
+      &clients,
+      &domainlists(),
+      &IdentifiedList::new(vec![]),
+      &exhausted,
+      now,
+    );
+    assert_eq!(
+      (
+        reason.client.as_str(),
+        reason.list.as_str(),
+        reason.rule.as_str()
+      ),
+      ("This device", "a blocked list", "a rule")
+    );
+  }
+
+  #[test]
+  fn renders() {
+    let reason = Reason {
+      client: "Sam's <laptop>".to_owned(),
+      list: "'Games'".to_owned(),
+      rule: "rule 1".to_owned(),
+      url: "http://example.com/?a=1&b=2".to_owned(),
+      lease_ends: None,
+    };
+    assert_eq!(
+      render(
+        "<p>{{client}}: {{url}} ({{lease_ends}})</p><a href=\"{{request_access_url}}\">",
+        "https://chat.example.com/send?text={{client}}%20wants%20{{url}}",
+        &reason
+      ),
+      "<p>Sam&#39;s &lt;laptop&gt;: http://example.com/?a=1&amp;b=2 ()</p>\
+       <a href=\"https://chat.example.com/send?text=Sam%27s%20%3Claptop%3E%20wants%20\
+       http%3A%2F%2Fexample.com%2F%3Fa%3D1%26b%3D2\">"
+    );
+
+    // Placeholders in the values themselves are left as they are.
+    let reason = Reason {
+      url: "http://example.com/{{client}}".to_owned(),
+      ..reason
+    };
+    assert_eq!(
+      render("{{url}} {{unknown}} {{client", "", &reason),
+      "http://example.com/{{client}} {{unknown}} {{client"
+    );
+  }
+}
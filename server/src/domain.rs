@@ -0,0 +1,770 @@
+use std::collections::{HashMap, HashSet};
+use std::iter::Peekable;
+use std::str::Chars;
+
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+
+use crate::list::IdentifiedList;
+use crate::model::{DomainList, ListKind};
+
+/// An entry in a domain list that couldn't be understood as a domain.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct InvalidDomain {
+  pub entry: String,
+  pub reason: String,
+}
+
+/// Two entries where one covers the other, e.g. ".example.com" covers "www.example.com". Squid
+/// refuses to load a dstdomain acl containing both.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Overlap {
+  pub domain: String,
+  pub domainlist: Option<u32>,
+  pub covered_by: String,
+  pub covered_by_domainlist: Option<u32>,
+}
+
+/// Extracts the host from a url as it appears in squid's logs, e.g. "http://example.com/path" or
+/// "example.com:443".
+pub fn url_host(url: &str) -> &str {
+  let rest = url.split_once("://").map(|(_, r)| r).unwrap_or(url);
+  let authority = rest.split(['/', '?', '#']).next().unwrap_or(rest);
+  let host = authority
+    .rsplit_once('@')
+    .map(|(_, h)| h)
+    .unwrap_or(authority);
+  if host.starts_with('[') {
+    host.split_inclusive(']').next().unwrap_or(host)
+  } else {
+    host.split(':').next().unwrap_or(host)
+  }
+}
+
+/// Extracts the path and query from a url, e.g. "/path?q=1" from "http://example.com/path?q=1".
+fn url_path(url: &str) -> &str {
+  let rest = url.split_once("://").map(|(_, r)| r).unwrap_or(url);
+  rest.find('/').map(|i| &rest[i..]).unwrap_or("")
+}
+
+/// Whether a host matches an entry in a dstdomain list. Entries starting with "." match the domain
+/// itself and all of its subdomains.
+pub fn domain_matches(host: &str, entry: &str) -> bool {
+  let host = host.trim_end_matches('.').to_lowercase();
+  let entry = entry.to_lowercase();
+  match entry.strip_prefix('.') {
+    Some(domain) => host == domain || host.ends_with(&entry),
+    None => host == entry,
+  }
+}
+
+/// The entries that would match everything `entry` does, other than `entry` itself: the wildcard
+/// form of its domain, and the wildcard form of each domain above it. For example, "www.example.com"
+/// is covered by ".www.example.com", ".example.com" and ".com". Looking these up in a set is much
+/// faster than comparing every pair of entries in a large list.
+fn covering_entries(entry: &str) -> impl Iterator<Item = String> + '_ {
+  let name = entry.trim_start_matches('.');
+  let wildcard = (!entry.starts_with('.')).then(|| format!(".{}", name));
+  wildcard.into_iter().chain(
+    name
+      .match_indices('.')
+      .map(move |(i, _)| name[i..].to_owned()),
+  )
+}
+
+/// Entries keyed by their lowercase form, for finding the entries that cover another.
+fn entry_index(entries: &[String]) -> HashMap<String, String> {
+  entries
+    .iter()
+    .rev()
+    .map(|e| (e.to_lowercase(), e.clone()))
+    .collect()
+}
+
+/// Finds an entry in an index that's the same as `entry` or covers it.
+fn find_covering<'a>(index: &'a HashMap<String, String>, entry: &str) -> Option<&'a String> {
+  let entry = entry.to_lowercase();
+  let covering = std::iter::once(entry.clone())
+    .chain(covering_entries(&entry))
+    .find_map(|e| index.get(&e));
+  covering
+}
+
+fn is_valid_label(label: &str) -> bool {
+  !label.is_empty()
+    && label.len() <= 63
+    && !label.starts_with('-')
+    && !label.ends_with('-')
+    && label
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Turns an entry as someone might type or paste it, e.g. "https://WWW.Example.com/path" or
+/// "*.bücher.de", into the form squid expects in a dstdomain acl.
+pub fn normalize_domain(entry: &str) -> Result<String, String> {
+  let mut domain = entry.trim().to_lowercase();
+  if domain.is_empty() {
+    return Err("Empty entry".to_owned());
+  }
+
+  if let Some((_, rest)) = domain.split_once("://") {
+    domain = rest.to_owned();
+  }
+  domain = domain
+    .split(['/', '?', '#'])
+    .next()
+    .unwrap_or_default()
+    .to_owned();
+  if let Some((_, host)) = domain.rsplit_once('@') {
+    domain = host.to_owned();
+  }
+  if let Some((host, port)) = domain.split_once(':') {
+    if port.is_empty() || !port.chars().all(|c| c.is_ascii_digit()) {
+      return Err("Invalid port".to_owned());
+    }
+    domain = host.to_owned();
+  }
+
+  // "*.example.com" means the same to people as ".example.com" does to squid.
+  let (wildcard, name) = match domain.strip_prefix("*.").or(domain.strip_prefix('.')) {
+    Some(name) => (true, name),
+    None => (false, domain.as_str()),
+  };
+  let name = name.trim_end_matches('.');
+
+  let name = idna::domain_to_ascii(name).map_err(|_| "Invalid international domain".to_owned())?;
+  if name.len() > 253 {
+    return Err("Domain is too long".to_owned());
+  }
+  if !name.split('.').all(is_valid_label) {
+    return Err("Not a valid domain".to_owned());
+  }
+
+  Ok(if wildcard { format!(".{}", name) } else { name })
+}
+
+/// Removes entries that are duplicates of, or covered by, another entry, keeping the order of the
+/// rest.
+pub fn remove_overlaps(domains: &[String]) -> Vec<String> {
+  let mut seen = HashSet::new();
+  let unique: Vec<_> = domains
+    .iter()
+    .map(|d| d.to_lowercase())
+    .filter(|d| seen.insert(d.clone()))
+    .collect();
+
+  unique
+    .iter()
+    .filter(|d| !covering_entries(d).any(|other| seen.contains(&other)))
+    .cloned()
+    .collect()
+}
+
+/// Normalizes each entry of a domain list and removes any overlaps, or reports every entry that
+/// isn't a valid domain.
+pub fn normalize_domains(entries: &[String]) -> Result<Vec<String>, Vec<InvalidDomain>> {
+  let mut domains = Vec::new();
+  let mut invalid = Vec::new();
+  for entry in entries {
+    match normalize_domain(entry) {
+      Ok(domain) => domains.push(domain),
+      Err(reason) => invalid.push(InvalidDomain {
+        entry: entry.clone(),
+        reason,
+      }),
+    }
+  }
+
+  if invalid.is_empty() {
+    Ok(remove_overlaps(&domains))
+  } else {
+    Err(invalid)
+  }
+}
+
+fn compile(pattern: &str) -> Result<Regex, regex::Error> {
+  RegexBuilder::new(pattern).case_insensitive(true).build()
+}
+
+/// Skips the rest of a bracket expression, after its opening `[`. Returns the first escape like `\d`
+/// in it, which POSIX would take as a backslash and a letter.
+fn skip_bracket_expression(chars: &mut Peekable<Chars>) -> Option<char> {
+  let mut escape = None;
+  chars.next_if_eq(&'^');
+  // A `]` straight after the opening bracket is part of the expression.
+  chars.next_if_eq(&']');
+  while let Some(c) = chars.next() {
+    match c {
+      ']' => break,
+      '[' if matches!(chars.peek(), Some(':' | '.' | '=')) => {
+        let delimiter = chars.next().unwrap();
+        while let Some(c) = chars.next() {
+          if c == delimiter && chars.next_if_eq(&']').is_some() {
+            break;
+          }
+        }
+      }
+      '\\' if escape.is_none() => {
+        escape = chars.next_if(|e| matches!(e, 'd' | 'D' | 's' | 'S' | 'w' | 'W'));
+      }
+      _ => {}
+    }
+  }
+  escape
+}
+
+/// Finds syntax that the regex crate accepts but squid's POSIX extended regular expressions don't.
+/// Patterns are checked here but matched by squid, so they'd fail to load or match something else.
+fn posix_incompatibility(pattern: &str) -> Option<String> {
+  let mut chars = pattern.chars().peekable();
+  // The last character that could be followed by a quantifier, if it was itself a quantifier.
+  let mut quantifier = None;
+  while let Some(c) = chars.next() {
+    match c {
+      '\\' => {
+        if let Some(e @ ('d' | 'D')) = chars.next() {
+          let class = if e == 'd' { "[0-9]" } else { "[^0-9]" };
+          return Some(format!(
+            "\\{} isn't supported by squid. Use {} instead",
+            e, class
+          ));
+        }
+        quantifier = None;
+      }
+      '[' => {
+        if let Some(e) = skip_bracket_expression(&mut chars) {
+          return Some(format!("\\{} can't be used inside [...] in squid", e));
+        }
+        quantifier = None;
+      }
+      '(' if chars.peek() == Some(&'?') => {
+        return Some("Groups starting with (? aren't supported by squid".to_owned());
+      }
+      '?' if quantifier.is_some() => {
+        return Some(format!(
+          "Lazy quantifiers like {}? aren't supported by squid",
+          quantifier.unwrap()
+        ));
+      }
+      '*' | '+' | '?' | '}' => quantifier = Some(c),
+      _ => quantifier = None,
+    }
+  }
+  None
+}
+
+/// Checks each entry of a list according to its kind, normalizing domains or making sure regular
+/// expressions compile, and reports every entry that isn't valid.
+pub fn normalize_list(list: &DomainList) -> Result<Vec<String>, Vec<InvalidDomain>> {
+  if list.kind.is_dstdomain() {
+    return normalize_domains(&list.domains);
+  }
+
+  let mut patterns = Vec::new();
+  let mut invalid = Vec::new();
+  for entry in list.domains.iter() {
+    let pattern = entry.trim();
+    let reason = if pattern.is_empty() {
+      Some("Empty entry".to_owned())
+    } else if pattern.contains(char::is_whitespace) {
+      Some("Regular expressions can't contain whitespace. Use \\s instead".to_owned())
+    } else {
+      compile(pattern)
+        .err()
+        .map(|e| e.to_string())
+        .or_else(|| posix_incompatibility(pattern))
+    };
+    match reason {
+      None if !patterns.iter().any(|p| p == pattern) => patterns.push(pattern.to_owned()),
+      None => {}
+      Some(reason) => invalid.push(InvalidDomain {
+        entry: entry.clone(),
+        reason,
+      }),
+    }
+  }
+
+  if invalid.is_empty() {
+    Ok(patterns)
+  } else {
+    Err(invalid)
+  }
+}
+
+/// Matches urls against a domain list the same way squid would.
+pub struct ListMatcher {
+  kind: ListKind,
+  domains: Vec<String>,
+  regexes: Vec<Regex>,
+}
+
+impl ListMatcher {
+  pub fn new(list: &DomainList) -> Self {
+    let regexes = if list.kind.is_dstdomain() {
+      Vec::new()
+    } else {
+      // Invalid expressions are rejected when the list is saved, so they're just ignored here.
+      list
+        .domains
+        .iter()
+        .filter_map(|d| compile(d).ok())
+        .collect()
+    };
+
+    ListMatcher {
+      kind: list.kind,
+      domains: list.domains.clone(),
+      regexes,
+    }
+  }
+
+  /// Whether a url, as it appears in squid's logs, matches the list.
+  pub fn matches(&self, url: &str) -> bool {
+    let subject = match self.kind {
+      ListKind::Dstdomain => {
+        let host = url_host(url);
+        return self.domains.iter().any(|d| domain_matches(host, d));
+      }
+      ListKind::DstdomRegex => url_host(url),
+      ListKind::UrlRegex => url,
+      ListKind::UrlpathRegex => url_path(url),
+    };
+    self.regexes.iter().any(|r| r.is_match(subject))
+  }
+}
+
+fn visit_includes<'a>(
+  lists: &'a IdentifiedList<DomainList>,
+  id: u32,
+  path: &mut Vec<u32>,
+  result: &mut Vec<&'a DomainList>,
+) -> Result<(), String> {
+  if path.contains(&id) {
+    let cycle: Vec<_> = path.iter().chain([&id]).map(|i| i.to_string()).collect();
+    return Err(format!(
+      "Domain lists include each other: {}",
+      cycle.join(" -> ")
+    ));
+  }
+  if result.iter().any(|l| l.id == Some(id)) {
+    return Ok(());
+  }
+  let Some(list) = lists.items.iter().find(|l| l.id == Some(id)) else {
+    return Ok(());
+  };
+
+  path.push(id);
+  result.push(list);
+  for include in list.includes.iter() {
+    visit_includes(lists, *include, path, result)?;
+  }
+  path.pop();
+
+  Ok(())
+}
+
+/// Finds a list and every list it includes, directly or indirectly. Includes of lists that don't
+/// exist are ignored, but lists that include each other are an error.
+pub fn resolve_includes(
+  lists: &IdentifiedList<DomainList>,
+  id: u32,
+) -> Result<Vec<&DomainList>, String> {
+  let mut result = Vec::new();
+  visit_includes(lists, id, &mut Vec::new(), &mut result)?;
+  Ok(result)
+}
+
+/// Combines a list's domains with those of every list it includes.
+pub fn flatten(
+  lists: &IdentifiedList<DomainList>,
+  list: &DomainList,
+) -> Result<DomainList, String> {
+  let mut flattened = list.clone();
+  if list.includes.is_empty() {
+    return Ok(flattened);
+  }
+
+  let mut domains = list.domains.clone();
+  for include in list.includes.iter() {
+    for member in resolve_includes(lists, *include)? {
+      domains.extend(member.domains.iter().cloned());
+    }
+  }
+  flattened.domains = if list.kind.is_dstdomain() {
+    remove_overlaps(&domains)
+  } else {
+    let mut seen = HashSet::new();
+    domains
+      .into_iter()
+      .filter(|d| seen.insert(d.clone()))
+      .collect()
+  };
+
+  Ok(flattened)
+}
+
+/// An entry that wasn't imported because the list already covers it.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SkippedDomain {
+  pub entry: String,
+  pub covered_by: String,
+}
+
+/// What importing entries into a domain list did, or would do.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ImportReport {
+  pub added: Vec<String>,
+  pub skipped: Vec<SkippedDomain>,
+  pub rejected: Vec<InvalidDomain>,
+}
+
+/// Adds entries to a list's existing domains, returning the list's new domains along with a report
+/// of which entries were added, skipped as already covered, or rejected as invalid.
+pub fn merge_domains(existing: &[String], entries: &[String]) -> (Vec<String>, ImportReport) {
+  let mut domains = existing.to_vec();
+  let mut index = entry_index(existing);
+  let mut report = ImportReport {
+    added: Vec::new(),
+    skipped: Vec::new(),
+    rejected: Vec::new(),
+  };
+
+  for entry in entries {
+    match normalize_domain(entry) {
+      Err(reason) => report.rejected.push(InvalidDomain {
+        entry: entry.clone(),
+        reason,
+      }),
+      Ok(domain) => match find_covering(&index, &domain) {
+        Some(covered_by) => report.skipped.push(SkippedDomain {
+          entry: entry.clone(),
+          covered_by: covered_by.clone(),
+        }),
+        None => {
+          report.added.push(domain.clone());
+          index.insert(domain.to_lowercase(), domain.clone());
+          domains.push(domain);
+        }
+      },
+    }
+  }
+
+  (remove_overlaps(&domains), report)
+}
+
+/// Finds entries that are covered by an entry in another of the lists. Only dstdomain lists can
+/// overlap.
+pub fn find_overlaps(lists: &[&DomainList]) -> Vec<Overlap> {
+  let lists: Vec<_> = lists
+    .iter()
+    .filter(|l| l.kind.is_dstdomain())
+    .map(|l| (l, entry_index(&l.domains)))
+    .collect();
+  let mut overlaps = Vec::new();
+  for (list, _) in lists.iter() {
+    for (other, index) in lists.iter().filter(|(o, _)| o.id != list.id) {
+      for domain in list.domains.iter() {
+        if let Some(covered_by) = find_covering(index, domain) {
+          // An identical entry in two lists is only reported once.
+          if covered_by == domain && other.id > list.id {
+            continue;
+          }
+          overlaps.push(Overlap {
+            domain: domain.clone(),
+            domainlist: list.id,
+            covered_by: covered_by.clone(),
+            covered_by_domainlist: other.id,
+          });
+        }
+      }
+    }
+  }
+
+  overlaps
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn strings(entries: &[&str]) -> Vec<String> {
+    entries.iter().map(|e| e.to_string()).collect()
+  }
+
+  #[test]
+  fn hosts() {
+    assert_eq!(
+      url_host("http://www.example.com/path?q=1"),
+      "www.example.com"
+    );
+    assert_eq!(
+      url_host("weather-data.apple.com:443"),
+      "weather-data.apple.com"
+    );
+    assert_eq!(url_host("http://user:pw@example.com:8080/"), "example.com");
+    assert_eq!(url_host("[::1]:443"), "[::1]");
+    assert_eq!(url_path("http://www.example.com/path?q=1"), "/path?q=1");
+    assert_eq!(url_path("weather-data.apple.com:443"), "");
+  }
+
+  #[test]
+  fn matches_domains() {
+    assert!(domain_matches("youtube.com", ".youtube.com"));
+    assert!(domain_matches("www.YouTube.com", ".youtube.com"));
+    assert!(!domain_matches("notyoutube.com", ".youtube.com"));
+    assert!(domain_matches("youtube.com", "youtube.com"));
+    assert!(!domain_matches("www.youtube.com", "youtube.com"));
+  }
+
+  #[test]
+  fn normalizes() {
+    assert_eq!(
+      normalize_domain(" https://WWW.Example.com:443/path?q=1 "),
+      Ok("www.example.com".to_owned())
+    );
+    assert_eq!(
+      normalize_domain("*.example.com"),
+      Ok(".example.com".to_owned())
+    );
+    assert_eq!(
+      normalize_domain(".Example.com."),
+      Ok(".example.com".to_owned())
+    );
+    assert_eq!(
+      normalize_domain("bücher.de"),
+      Ok("xn--bcher-kva.de".to_owned())
+    );
+  }
+
+  #[test]
+  fn rejects_invalid() {
+    assert!(normalize_domain("").is_err());
+    assert!(normalize_domain("not a domain").is_err());
+    assert!(normalize_domain("example..com").is_err());
+    assert!(normalize_domain("-example.com").is_err());
+    assert!(normalize_domain("example.com:http").is_err());
+
+    assert_eq!(
+      normalize_domains(&strings(&["example.com", "bad domain", ""])),
+      Err(vec![
+        InvalidDomain {
+          entry: "bad domain".to_owned(),
+          reason: "Not a valid domain".to_owned()
+        },
+        InvalidDomain {
+          entry: "".to_owned(),
+          reason: "Empty entry".to_owned()
+        },
+      ])
+    );
+  }
+
+  #[test]
+  fn removes_overlaps() {
+    assert_eq!(
+      normalize_domains(&strings(&[
+        "www.example.com",
+        "Example.com",
+        "*.example.com",
+        "example.org",
+        "EXAMPLE.org",
+      ])),
+      Ok(strings(&[".example.com", "example.org"]))
+    );
+  }
+
+  #[test]
+  fn removes_overlaps_in_large_lists() {
+    let mut domains: Vec<_> = (0..100_000)
+      .map(|i| format!("host{}.example{}.com", i, i % 100))
+      .collect();
+    domains.push(".example7.com".to_owned());
+    // Entries differing only in case are duplicates.
+    domains.push("HOST8.example8.com".to_owned());
+
+    let kept = remove_overlaps(&domains);
+    assert_eq!(kept.len(), 100_000 - 1000 + 1);
+    assert!(kept.contains(&".example7.com".to_owned()));
+    assert!(!kept.contains(&"host7.example7.com".to_owned()));
+  }
+
+  #[test]
+  fn finds_overlaps() {
+    let list = |id, domains: &[&str]| DomainList {
+      id: Some(id),
+      name: format!("list {}", id),
+      kind: ListKind::Dstdomain,
+      domains: strings(domains),
+      includes: vec![],
+      source: None,
+    };
+    let video = list(1, &[".youtube.com", "vimeo.com"]);
+    let music = list(2, &["music.youtube.com", "vimeo.com", "spotify.com"]);
+
+    assert_eq!(
+      find_overlaps(&[&video, &music]),
+      vec![
+        Overlap {
+          domain: "music.youtube.com".to_owned(),
+          domainlist: Some(2),
+          covered_by: ".youtube.com".to_owned(),
+          covered_by_domainlist: Some(1),
+        },
+        Overlap {
+          domain: "vimeo.com".to_owned(),
+          domainlist: Some(2),
+          covered_by: "vimeo.com".to_owned(),
+          covered_by_domainlist: Some(1),
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn regex_lists() {
+    let list = |kind, domains: &[&str]| DomainList {
+      id: Some(1),
+      name: "games".to_owned(),
+      kind,
+      domains: strings(domains),
+      includes: vec![],
+      source: None,
+    };
+
+    assert_eq!(
+      normalize_list(&list(
+        ListKind::DstdomRegex,
+        &["cdn-games-.*\\.net$", "(", "a b"]
+      )),
+      Err(vec![
+        InvalidDomain {
+          entry: "(".to_owned(),
+          reason: compile("(").unwrap_err().to_string(),
+        },
+        InvalidDomain {
+          entry: "a b".to_owned(),
+          reason: "Regular expressions can't contain whitespace. Use \\s instead".to_owned(),
+        },
+      ])
+    );
+
+    let reasons = |patterns: &[&str]| -> Vec<String> {
+      match normalize_list(&list(ListKind::UrlRegex, patterns)) {
+        Ok(_) => vec![],
+        Err(invalid) => invalid.into_iter().map(|i| i.reason).collect(),
+      }
+    };
+    assert_eq!(
+      reasons(&[
+        "(?i)games",
+        "level\\d+",
+        "[\\D]",
+        "a.*?b",
+        "a+?",
+        "a??",
+        "a{2,3}?"
+      ]),
+      vec![
+        "Groups starting with (? aren't supported by squid",
+        "\\d isn't supported by squid. Use [0-9] instead",
+        "\\D can't be used inside [...] in squid",
+        "Lazy quantifiers like *? aren't supported by squid",
+        "Lazy quantifiers like +? aren't supported by squid",
+        "Lazy quantifiers like ?? aren't supported by squid",
+        "Lazy quantifiers like }? aren't supported by squid",
+      ]
+    );
+    assert!(reasons(&[
+      "\\*?",
+      "[*?]+",
+      "[]?]",
+      "[[:digit:]]?x",
+      "(ab)?",
+      "\\.games\\.example$",
+      "\\sx\\w",
+      "[\\.]"
+    ])
+    .is_empty());
+
+    let hosts = ListMatcher::new(&list(ListKind::DstdomRegex, &["cdn-games-.*\\.net$"]));
+    assert!(hosts.matches("static.CDN-games-eu.net:443"));
+    assert!(!hosts.matches("http://example.com/cdn-games-eu.net"));
+
+    let paths = ListMatcher::new(&list(ListKind::UrlpathRegex, &["^/shorts/"]));
+    assert!(paths.matches("http://www.youtube.com/shorts/abc"));
+    assert!(!paths.matches("http://www.youtube.com/watch?v=abc"));
+
+    let urls = ListMatcher::new(&list(ListKind::UrlRegex, &["youtube\\.com/shorts"]));
+    assert!(urls.matches("http://www.youtube.com/shorts/abc"));
+  }
+
+  #[test]
+  fn merges() {
+    let (domains, report) = merge_domains(
+      &strings(&["www.casino.example", ".poker.example"]),
+      &strings(&[
+        "*.casino.example",
+        "tables.poker.example",
+        "slots.example",
+        "slots.example",
+        "not a domain",
+      ]),
+    );
+
+    assert_eq!(
+      domains,
+      strings(&[".poker.example", ".casino.example", "slots.example"])
+    );
+    assert_eq!(
+      report,
+      ImportReport {
+        added: strings(&[".casino.example", "slots.example"]),
+        skipped: vec![
+          SkippedDomain {
+            entry: "tables.poker.example".to_owned(),
+            covered_by: ".poker.example".to_owned(),
+          },
+          SkippedDomain {
+            entry: "slots.example".to_owned(),
+            covered_by: "slots.example".to_owned(),
+          },
+        ],
+        rejected: vec![InvalidDomain {
+          entry: "not a domain".to_owned(),
+          reason: "Not a valid domain".to_owned(),
+        }],
+      }
+    );
+  }
+
+  #[test]
+  fn includes() {
+    let list = |id, domains: &[&str], includes: Vec<u32>| DomainList {
+      id: Some(id),
+      name: format!("list {}", id),
+      kind: ListKind::Dstdomain,
+      domains: strings(domains),
+      includes,
+      source: None,
+    };
+    let mut lists = IdentifiedList::new(vec![
+      list(1, &["facebook.com"], vec![]),
+      list(2, &[".youtube.com"], vec![]),
+      list(3, &["www.youtube.com", "roblox.com"], vec![2]),
+      list(4, &[], vec![1, 3, 5]),
+    ]);
+
+    let distractions = flatten(&lists, &lists.items[3]).unwrap();
+    assert_eq!(
+      distractions.domains,
+      strings(&["facebook.com", "roblox.com", ".youtube.com"])
+    );
+
+    lists.items[1].includes = vec![4];
+    assert_eq!(
+      resolve_includes(&lists, 4).err(),
+      Some("Domain lists include each other: 4 -> 3 -> 2 -> 4".to_owned())
+    );
+  }
+}
@@ -7,6 +7,8 @@ pub enum MyError {
   Failed(anyhow::Error),
   NotFound,
   BadRequest(String),
+  // A bad request, along with details of what was wrong as json
+  BadRequestDetails(String, serde_json::Value),
   NotAuthorized
 }
 
@@ -30,6 +32,13 @@ impl IntoResponse for MyError {
         tracing::error!("Bad request: {:?}", m);
         (axum::http::StatusCode::BAD_REQUEST, m.to_owned()).into_response()
       }
+      MyError::BadRequestDetails(m, details) => {
+        tracing::error!("Bad request: {:?} {}", m, details);
+        (
+          axum::http::StatusCode::BAD_REQUEST,
+          axum::Json(serde_json::json!({ "message": m, "details": details })),
+        ).into_response()
+      }
       MyError::Failed(e) => {
         tracing::error!("Internal error: {:?}", e);
         (
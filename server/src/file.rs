@@ -2,7 +2,7 @@ use anyhow::{anyhow, Result};
 use serde_json::Value;
 use std::{
   fs::File,
-  io::{BufReader, BufWriter, LineWriter, Write},
+  io::{BufReader, BufWriter},
   path::Path,
 };
 
@@ -34,29 +34,3 @@ pub fn create_file<P: AsRef<Path>>(path: P) -> Result<File> {
   std::fs::create_dir_all(parent)?;
   Ok(File::create(path)?)
 }
-
-pub fn create_writer<P: AsRef<Path>, S: Into<String>>(
-  base_path: P,
-  filename: S,
-) -> Result<NiceLineWriter<File>> {
-  let path = base_path.as_ref().join(filename.into());
-  Ok(NiceLineWriter::new(create_file(path)?))
-}
-
-pub struct NiceLineWriter<T: Write> {
-  inner: LineWriter<T>,
-}
-
-impl<T: Write> NiceLineWriter<T> {
-  fn new(write: T) -> Self {
-    Self {
-      inner: LineWriter::new(write),
-    }
-  }
-
-  pub fn writeln<S: Into<String>>(&mut self, s: S) -> Result<()> {
-    self.inner.write_all(s.into().as_bytes())?;
-    self.inner.write_all(b"\n")?;
-    Ok(())
-  }
-}
@@ -1,13 +1,20 @@
-use crate::file::create_writer;
+use crate::blockpage::host_and_port;
+use crate::domain::{flatten, remove_overlaps};
 use crate::list::Identifiable;
-use crate::model::RuleKind;
+use crate::model::{Lease, RuleKind};
+use crate::quota::ExhaustedQuotas;
+use crate::schedule::squid_time_specs;
 use crate::{
   list::IdentifiedList,
   model::{Client, DomainList},
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use chrono::Utc;
+use sha2::{Digest, Sha256};
+use similar::TextDiff;
+use std::collections::BTreeMap;
 use std::fs;
+use std::net::IpAddr;
 use std::path::Path;
 
 impl Identifiable for u32 {
@@ -20,71 +27,1354 @@ impl Identifiable for u32 {
   }
 }
 
+/// Generated configuration files, by file name.
+pub type ConfigFiles = BTreeMap<String, String>;
+
+/// A generated file, built up a line at a time.
+#[derive(Default)]
+struct ConfigFile(String);
+
+impl ConfigFile {
+  fn writeln<S: AsRef<str>>(&mut self, line: S) {
+    self.0.push_str(line.as_ref());
+    self.0.push('\n');
+  }
+}
+
+/// An acl that matches every request, for denying everything with a block page.
+const BLOCKED_ALL_ACL: &str = "blocked_all";
+/// An acl that matches requests for the block page itself.
+const BLOCK_PAGE_ACL: &str = "block_page";
+
+/// A deny_info line that sends requests an acl denies to the block page. Squid fills in the
+/// client's address and the url.
+fn deny_info(url: &str, params: &str, acl: &str) -> String {
+  let separator = if url.contains('?') { '&' } else { '?' };
+  format!(
+    "deny_info {}{}{}ip=%i&url=%U {}",
+    url, separator, params, acl
+  )
+}
+
+fn exceptions_acl(client_name: &str, rule_index: usize) -> String {
+  format!("{}_rule_{}_exceptions", client_name, rule_index)
+}
+
+fn mime_types_acl(client_name: &str, rule_index: usize) -> String {
+  format!("{}_rule_{}_mime_types", client_name, rule_index)
+}
+
+fn file_extensions_acl(client_name: &str, rule_index: usize) -> String {
+  format!("{}_rule_{}_file_extensions", client_name, rule_index)
+}
+
+fn escape_regex(s: &str) -> String {
+  s.chars()
+    .flat_map(|c| match c {
+      '.' | '+' | '*' | '?' | '(' | ')' | '[' | ']' | '{' | '}' | '|' | '^' | '$' | '\\' => {
+        vec!['\\', c]
+      }
+      c => vec![c],
+    })
+    .collect()
+}
+
+/// Turns a content type like "video/mp4" or "video/*" into a regex matching the Content-Type header
+/// of a reply, which may have parameters like "; charset=utf-8".
+fn mime_type_regex(mime_type: &str) -> String {
+  match mime_type.strip_suffix("/*") {
+    Some(media_type) => format!("^{}/", escape_regex(media_type)),
+    None => format!("^{}($|;)", escape_regex(mime_type)),
+  }
+}
+
+/// Makes a regex matching url paths that end in any of the given file extensions, ignoring any
+/// query string.
+fn file_extensions_regex(extensions: &[String]) -> String {
+  let extensions: Vec<_> = extensions
+    .iter()
+    .map(|e| escape_regex(&e.trim_start_matches('.').to_lowercase()))
+    .collect();
+  format!("\\.({})(\\?.*)?$", extensions.join("|"))
+}
+
+/// A site that can be made to filter what it shows by sending its traffic to a different host, the
+/// same way the site's own instructions for enforcing it through DNS do.
+struct SafeSearchSite {
+  name: &'static str,
+  acl: &'static str,
+  host: &'static str,
+}
+
+const SAFE_SEARCH_SITES: [SafeSearchSite; 4] = [
+  SafeSearchSite {
+    name: "google",
+    acl: "dstdom_regex -i ^(www\\.)?google\\.[a-z]+(\\.[a-z]+)?$",
+    host: "forcesafesearch.google.com",
+  },
+  SafeSearchSite {
+    name: "bing",
+    acl: "dstdomain www.bing.com",
+    host: "strict.bing.com",
+  },
+  SafeSearchSite {
+    name: "duckduckgo",
+    acl: "dstdomain duckduckgo.com www.duckduckgo.com",
+    host: "safe.duckduckgo.com",
+  },
+  SafeSearchSite {
+    name: "youtube",
+    acl: "dstdomain youtube.com www.youtube.com m.youtube.com youtubei.googleapis.com \
+          youtube.googleapis.com www.youtube-nocookie.com",
+    host: "restrict.youtube.com",
+  },
+];
+
 fn id_string<T: Identifiable>(type_name: &str, item: &T) -> String {
   format!("{}_{:0>4}", type_name, item.id().unwrap())
 }
 
+/// Generates squid configuration for the given clients and lists. Each list's domains go in a file
+/// of their own, which the configuration refers to by its path in `list_dir`, the directory squid
+/// will find the files in. Denied requests are sent to `block_page_url`, if there is one.
 pub fn generate_squid_config<P: AsRef<Path>>(
-  out_dir: P,
+  list_dir: P,
   clients: &IdentifiedList<Client>,
   domainlists: &IdentifiedList<DomainList>,
-) -> Result<()> {
-  let out_dir = out_dir.as_ref();
-  fs::create_dir_all(out_dir)?;
+  global_leases: &IdentifiedList<Lease>,
+  exhausted_quotas: &ExhaustedQuotas,
+  block_page_url: Option<&str>,
+) -> Result<ConfigFiles> {
+  let list_dir = list_dir.as_ref();
+  let mut files = ConfigFiles::new();
 
-  for client in clients.items.iter().filter(|c| !c.rules.is_empty()) {
+  // Squid uses the deny_info of the last acl on the line that denied a request, so lines that would
+  // end with `all` end with an acl of penguin's own instead when there's a block page.
+  let all = if block_page_url.is_some() {
+    BLOCKED_ALL_ACL
+  } else {
+    "all"
+  };
+
+  // Delay pools are numbered across all clients, and squid needs to know how many there are before
+  // any of them are configured.
+  let mut delay_pools = 0;
+
+  for client in clients.items.iter().filter(|c| {
+    !c.rules.is_empty()
+      || !c.leases.is_empty()
+      || !global_leases.items.is_empty()
+      || c.bandwidth.is_some()
+      || c.safe_search
+  }) {
     let client_name = id_string("client", client);
-    let mut b = create_writer(out_dir, format!("{}.conf", client_name))?;
+    let mut b = ConfigFile::default();
 
-    // First, figure out if there are any domains that are temporarily allowed due to a lease rule.
+    // Global leases apply to every client, just as if each client had them. Each lease is named
+    // in case it needs an acl of its own.
     let now = Utc::now();
-    let allowed_domains: Vec<_> = client
+    let leases: Vec<_> = client
       .leases
       .iter()
-      .filter(|l| l.rule.kind == RuleKind::AllowHttpAccess && l.end_date_utc.unwrap() > now)
-      .flat_map(|l| l.rule.domainlists.iter())
+      .enumerate()
+      .map(|(index, l)| (format!("{}_lease_{}", client_name, index), l))
+      .chain(
+        global_leases
+          .items
+          .iter()
+          .enumerate()
+          .map(|(index, l)| (format!("{}_global_lease_{}", client_name, index), l)),
+      )
+      .filter(|(_, l)| l.is_active(now))
       .collect();
 
-    b.writeln(format!("acl {} src {}", client_name, client.ip))?;
-    for domain in client
-      .rules
+    // First, figure out if there are any domains that are temporarily allowed due to a lease rule.
+    let allowed_domains: Vec<_> = leases
       .iter()
-      .filter(|r| r.kind == RuleKind::DenyHttpAccess)
-      .flat_map(|r| r.domainlists.iter())
+      .filter(|(_, l)| l.rule.kind == RuleKind::AllowHttpAccess)
+      .flat_map(|(_, l)| l.rule.domainlists.iter())
+      .collect();
+
+    b.writeln(format!("acl {} src {}", client_name, client.ip));
+
+    // Domains that a lease lists directly, rather than through a domain list, get their own acl.
+    for (lease_acl, lease) in leases.iter().filter(|(_, l)| !l.rule.domains.is_empty()) {
+      b.writeln(format!(
+        "acl {} dstdomain {}",
+        lease_acl,
+        remove_overlaps(&lease.rule.domains).join(" ")
+      ));
+      if let Some(url) = block_page_url {
+        b.writeln(deny_info(url, "", lease_acl));
+      }
+    }
+
+    // The acls that must match for each rule to apply: the client, and the rule's schedule if it
+    // has one.
+    let mut rule_acls = Vec::new();
+    for (index, rule) in client.rules.iter().enumerate() {
+      if !rule.exception_domains.is_empty() {
+        b.writeln(format!(
+          "acl {} dstdomain {}",
+          exceptions_acl(&client_name, index),
+          remove_overlaps(&rule.exception_domains).join(" ")
+        ));
+      }
+      if !rule.mime_types.is_empty() {
+        let patterns: Vec<_> = rule.mime_types.iter().map(|m| mime_type_regex(m)).collect();
+        b.writeln(format!(
+          "acl {} rep_mime_type -i {}",
+          mime_types_acl(&client_name, index),
+          patterns.join(" ")
+        ));
+        if let Some(url) = block_page_url {
+          b.writeln(deny_info(url, "", &mime_types_acl(&client_name, index)));
+        }
+      }
+      if !rule.file_extensions.is_empty() {
+        b.writeln(format!(
+          "acl {} urlpath_regex -i {}",
+          file_extensions_acl(&client_name, index),
+          file_extensions_regex(&rule.file_extensions)
+        ));
+        if let Some(url) = block_page_url {
+          b.writeln(deny_info(
+            url,
+            "",
+            &file_extensions_acl(&client_name, index),
+          ));
+        }
+      }
+      match &rule.schedule {
+        Some(schedule) => {
+          let time_acl = format!("{}_rule_{}_time", client_name, index);
+          for spec in squid_time_specs(schedule)? {
+            b.writeln(format!("acl {} time {}", time_acl, spec));
+          }
+          rule_acls.push(format!("{} {}", client_name, time_acl));
+        }
+        None => rule_acls.push(client_name.clone()),
+      }
+    }
+    let rules: Vec<_> = client.rules.iter().zip(rule_acls.iter()).collect();
+
+    // Deny leases take precedence over everything else, including allow leases. A deny lease
+    // without any domains or domain lists denies all access.
+    for (lease_acl, lease) in leases
+      .iter()
+      .filter(|(_, l)| l.rule.kind == RuleKind::DenyHttpAccess)
     {
-      if !allowed_domains.contains(&domain) {
+      if lease.rule.domainlists.is_empty() && lease.rule.domains.is_empty() {
+        b.writeln(format!("http_access deny {} {}", client_name, all));
+      }
+      if !lease.rule.domains.is_empty() {
+        b.writeln(format!("http_access deny {} {}", client_name, lease_acl));
+      }
+      for domain in lease.rule.domainlists.iter() {
+        b.writeln(format!(
+          "http_access deny {} {}",
+          client_name,
+          id_string("domains", domain)
+        ));
+      }
+    }
+
+    // Content types and file extensions are denied wherever they come from, even from sites that are
+    // otherwise allowed.
+    for (index, (rule, acls)) in rules.iter().enumerate() {
+      if rule.kind != RuleKind::DenyHttpAccess {
+        continue;
+      }
+      if !rule.file_extensions.is_empty() {
         b.writeln(format!(
           "http_access deny {} {}",
+          acls,
+          file_extensions_acl(&client_name, index)
+        ));
+      }
+      if !rule.mime_types.is_empty() {
+        b.writeln(format!(
+          "http_reply_access deny {} {}",
+          acls,
+          mime_types_acl(&client_name, index)
+        ));
+      }
+    }
+
+    // Domains allowed directly by a lease must come before any denies, so that they're allowed even
+    // if a rule denies a list that contains them.
+    for (lease_acl, _) in leases
+      .iter()
+      .filter(|(_, l)| l.rule.kind == RuleKind::AllowHttpAccess && !l.rule.domains.is_empty())
+    {
+      b.writeln(format!("http_access allow {} {}", client_name, lease_acl));
+    }
+
+    // Quota rules deny access just like deny rules, but only once the quota has been used up.
+    let denies: Vec<_> = rules
+      .iter()
+      .enumerate()
+      .filter(|(index, (r, _))| match r.kind {
+        RuleKind::DenyHttpAccess => true,
+        RuleKind::QuotaHttpAccess => exhausted_quotas.contains(&(client.id.unwrap(), *index)),
+        RuleKind::AllowHttpAccess => false,
+      })
+      .collect();
+
+    // Exceptions carve domains out of the lists a rule denies, so they're allowed before any denies.
+    for (index, (rule, acls)) in denies.iter() {
+      if !rule.exception_domains.is_empty() {
+        b.writeln(format!(
+          "http_access allow {} {}",
+          acls,
+          exceptions_acl(&client_name, *index)
+        ));
+      }
+      for domain in rule.exception_domainlists.iter() {
+        b.writeln(format!(
+          "http_access allow {} {}",
+          acls,
+          id_string("domains", domain)
+        ));
+      }
+    }
+
+    for (_, (rule, acls)) in denies {
+      for domain in rule.domainlists.iter() {
+        if !allowed_domains.contains(&domain) {
+          b.writeln(format!(
+            "http_access deny {} {}",
+            acls,
+            id_string("domains", domain)
+          ));
+        }
+      }
+    }
+
+    // A permanent allow rule puts the client into allowlist mode: only the domains in its lists
+    // (plus anything temporarily allowed by a lease) are reachable, and everything else is denied.
+    // If every allow rule is scheduled, allowlist mode only applies at the scheduled times.
+    let allow_rules: Vec<_> = rules
+      .iter()
+      .filter(|(r, _)| r.kind == RuleKind::AllowHttpAccess)
+      .collect();
+    if !allow_rules.is_empty() {
+      let mut allowlist: Vec<(&String, &u32)> = Vec::new();
+      for (rule, acls) in allow_rules.iter() {
+        allowlist.extend(rule.domainlists.iter().map(|d| (*acls, d)));
+      }
+      allowlist.extend(allowed_domains.iter().map(|d| (&client_name, *d)));
+
+      let mut written = Vec::new();
+      for entry in allowlist {
+        if !written.contains(&entry) {
+          let (acls, domain) = entry;
+          b.writeln(format!(
+            "http_access allow {} {}",
+            acls,
+            id_string("domains", domain)
+          ));
+          written.push(entry);
+        }
+      }
+
+      if allow_rules.iter().any(|(r, _)| r.schedule.is_none()) {
+        b.writeln(format!("http_access deny {} {}", client_name, all));
+      } else {
+        for (_, acls) in allow_rules.iter() {
+          b.writeln(format!("http_access deny {} {}", acls, all));
+        }
+      }
+    }
+
+    // Secure connections to search engines are tunnelled to their safe hosts instead, which serve
+    // the same certificates. YouTube also honours a header, for requests squid can see into.
+    if client.safe_search {
+      b.writeln(format!(
+        "request_header_add YouTube-Restrict strict {} safe_search_youtube",
+        client_name
+      ));
+      for site in SAFE_SEARCH_SITES.iter() {
+        b.writeln(format!(
+          "cache_peer_access safe_search_{} allow {} safe_search_connect safe_search_{}",
+          site.name, client_name, site.name
+        ));
+        b.writeln(format!(
+          "never_direct allow {} safe_search_connect safe_search_{}",
+          client_name, site.name
+        ));
+      }
+    }
+
+    // A class 1 pool throttles everything the client downloads through it to a single rate, with no
+    // allowance for bursts.
+    if let Some(bandwidth) = &client.bandwidth {
+      delay_pools += 1;
+      let rate = bandwidth.bytes_per_second();
+      b.writeln(format!("delay_class {} 1", delay_pools));
+      b.writeln(format!(
+        "delay_parameters {} {}/{}",
+        delay_pools, rate, rate
+      ));
+      if bandwidth.domainlists.is_empty() {
+        b.writeln(format!(
+          "delay_access {} allow {}",
+          delay_pools, client_name
+        ));
+      }
+      for domain in bandwidth.domainlists.iter() {
+        b.writeln(format!(
+          "delay_access {} allow {} {}",
+          delay_pools,
           client_name,
           id_string("domains", domain)
-        ))?;
+        ));
       }
+      b.writeln(format!("delay_access {} deny all", delay_pools));
     }
+
+    files.insert(format!("{}.conf", client_name), b.0);
   }
 
+  let mut b = ConfigFile::default();
+  b.writeln(format!("delay_pools {}", delay_pools));
+  files.insert("delay_pools.conf".to_owned(), b.0);
+
   // If there are no clients, we must nevertheless write out a dummy client_*.conf file, otherwise
   // squid will barf.
-  let dummy = out_dir.join("client_dummy.conf");
-  if !dummy.exists() {
-    let mut writer = create_writer(out_dir, "client_dummy.conf")?;
-    writer.writeln("# This file is intentionally left blank.")?;
-  }
+  let mut dummy = ConfigFile::default();
+  dummy.writeln("# This file is intentionally left blank.");
+  files.insert("client_dummy.conf".to_owned(), dummy.0);
 
-  let mut b = create_writer(out_dir, "domains.conf")?;
+  let mut b = ConfigFile::default();
   if !domainlists.items.is_empty() {
     for domainlist in domainlists.items.iter() {
       let domainlist_name = id_string("domains", domainlist);
+      // Each acl holds the domains of every list it includes, so rules only need to refer to it.
+      let flattened = flatten(domainlists, domainlist).map_err(|e| anyhow!(e))?;
+      let domains = if flattened.kind.is_dstdomain() {
+        remove_overlaps(&flattened.domains)
+      } else {
+        flattened.domains
+      };
+      // Subscribed lists can have many thousands of entries, so they're kept out of domains.conf.
+      let file_name = format!("{}.txt", domainlist_name);
+      let mut list_file = ConfigFile::default();
+      for domain in domains {
+        list_file.writeln(domain);
+      }
+      files.insert(file_name.clone(), list_file.0);
       b.writeln(format!(
-        "acl {} dstdomain {}",
+        "acl {} {} \"{}\"",
         domainlist_name,
-        domainlist.domains.join(" ")
-      ))?;
+        domainlist.kind.acl_type(),
+        list_dir.join(file_name).display()
+      ));
+      // The list is the last acl on every line that denies a list.
+      if let Some(url) = block_page_url {
+        b.writeln(deny_info(
+          url,
+          &format!("list={}&", domainlist.id.unwrap()),
+          &domainlist_name,
+        ));
+      }
     }
   } else {
     // We must always write out a domains.conf, otherwise squid will barf. If there are no domains,
     // just write one with a comment.
-    b.writeln("# This file will be populated with penguin domains")?;
+    b.writeln("# This file will be populated with penguin domains");
+  }
+
+  if let Some(url) = block_page_url {
+    // Browsers fetch the block page through squid too, so it's allowed before any client's lines
+    // can deny it. Otherwise clients that are denied everything would be redirected forever.
+    let (host, port) = host_and_port(url)?;
+    let kind = if host.parse::<IpAddr>().is_ok() {
+      "dst"
+    } else {
+      "dstdomain"
+    };
+    b.writeln(format!("acl {} {} {}", BLOCK_PAGE_ACL, kind, host));
+    b.writeln(format!("acl {}_port port {}", BLOCK_PAGE_ACL, port));
+    b.writeln(format!(
+      "http_access allow {} {}_port",
+      BLOCK_PAGE_ACL, BLOCK_PAGE_ACL
+    ));
+    b.writeln(format!("acl {} src all", BLOCKED_ALL_ACL));
+    b.writeln(deny_info(url, "", BLOCKED_ALL_ACL));
+  }
+
+  // Peers can only be defined once, so they're shared by every client that uses them. Each client
+  // allows itself to use them, and squid denies everyone else since the last cache_peer_access line
+  // for each peer is an allow.
+  if clients.items.iter().any(|c| c.safe_search) {
+    b.writeln("acl safe_search_connect method CONNECT");
+    for site in SAFE_SEARCH_SITES.iter() {
+      b.writeln(format!("acl safe_search_{} {}", site.name, site.acl));
+      b.writeln(format!(
+        "cache_peer {} parent 443 0 no-query no-digest originserver name=safe_search_{}",
+        site.host, site.name
+      ));
+    }
   }
 
+  files.insert("domains.conf".to_owned(), b.0);
+
+  Ok(files)
+}
+
+/// Writes generated configuration files to a directory.
+pub fn write_config_files(dir: &Path, files: &ConfigFiles) -> Result<()> {
+  fs::create_dir_all(dir)?;
+  for (name, content) in files.iter() {
+    fs::write(dir.join(name), content)?;
+  }
   Ok(())
 }
+
+/// The SHA-256 hash of each configuration file, by file name.
+pub type ConfigHashes = BTreeMap<String, String>;
+
+fn hash(content: &[u8]) -> String {
+  format!("{:x}", Sha256::digest(content))
+}
+
+pub fn hash_config_files(files: &ConfigFiles) -> ConfigHashes {
+  files
+    .iter()
+    .map(|(name, content)| (name.clone(), hash(content.as_bytes())))
+    .collect()
+}
+
+/// Hashes the files in a configuration directory, so it can be compared with generated files.
+pub fn hash_config_dir(dir: &Path) -> Result<ConfigHashes> {
+  let mut hashes = ConfigHashes::new();
+  if dir.exists() {
+    for entry in dir.read_dir()? {
+      let path = entry?.path();
+      if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+        hashes.insert(name.to_owned(), hash(&fs::read(&path)?));
+      }
+    }
+  }
+  Ok(hashes)
+}
+
+/// Reads the files in a configuration directory, so they can be compared with generated files.
+/// Anything penguin can't have written, such as subdirectories and files that aren't text, is
+/// skipped.
+pub fn read_config_dir(dir: &Path) -> Result<ConfigFiles> {
+  let mut files = ConfigFiles::new();
+  if dir.exists() {
+    for entry in dir.read_dir()? {
+      let entry = entry?;
+      if !entry.file_type()?.is_file() {
+        continue;
+      }
+      let path = entry.path();
+      let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        continue;
+      };
+      match String::from_utf8(fs::read(&path)?) {
+        Ok(text) => {
+          files.insert(name.to_owned(), text);
+        }
+        Err(_) => tracing::warn!("Skipping {}, which isn't text", path.display()),
+      }
+    }
+  }
+  Ok(files)
+}
+
+/// A unified diff between two sets of configuration files, with the files' names prefixed by
+/// `prefix`. Files that only exist in one set are diffed against an empty file.
+pub fn diff_config_files(prefix: &str, old: &ConfigFiles, new: &ConfigFiles) -> String {
+  let mut names: Vec<_> = old.keys().chain(new.keys()).collect();
+  names.sort();
+  names.dedup();
+
+  let mut diff = String::new();
+  for name in names {
+    let old_content = old.get(name).map_or("", |c| c.as_str());
+    let new_content = new.get(name).map_or("", |c| c.as_str());
+    if old_content == new_content {
+      continue;
+    }
+    let old_name = match old.contains_key(name) {
+      true => format!("a/{}/{}", prefix, name),
+      false => "/dev/null".to_owned(),
+    };
+    let new_name = match new.contains_key(name) {
+      true => format!("b/{}/{}", prefix, name),
+      false => "/dev/null".to_owned(),
+    };
+    diff.push_str(
+      &TextDiff::from_lines(old_content, new_content)
+        .unified_diff()
+        .header(&old_name, &new_name)
+        .to_string(),
+    );
+  }
+  diff
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::model::{BandwidthLimit, Day, ListKind, Rule, Schedule, TimeRange};
+  use chrono::Duration;
+
+  const LIST_DIR: &str = "/etc/squid/penguin.d";
+
+  fn client(rules: Vec<Rule>, leases: Vec<Lease>) -> Client {
+    Client {
+      id: Some(1),
+      ip: "192.168.1.33".to_owned(),
+      name: "Laptop".to_owned(),
+      rules,
+      leases,
+      mac_address: None,
+      bandwidth: None,
+      safe_search: false,
+    }
+  }
+
+  fn rule(kind: RuleKind, domainlists: Vec<u32>) -> Rule {
+    Rule {
+      kind,
+      domainlists,
+      domains: vec![],
+      exception_domains: vec![],
+      exception_domainlists: vec![],
+      mime_types: vec![],
+      file_extensions: vec![],
+      schedule: None,
+      daily_quota_minutes: None,
+    }
+  }
+
+  fn school_nights() -> Schedule {
+    Schedule {
+      days: vec![Day::Sun, Day::Mon, Day::Tue, Day::Wed, Day::Thu],
+      times: vec![TimeRange {
+        start: "19:00".to_owned(),
+        end: "07:00".to_owned(),
+      }],
+      timezone: None,
+    }
+  }
+
+  #[allow(deprecated)]
+  fn lease(rule: Rule) -> Lease {
+    Lease {
+      id: None,
+      start_date_utc: None,
+      end_date_utc: Some(Utc::now() + Duration::hours(1)),
+      end_date: None,
+      recurrence: None,
+      rule,
+      granted_by: None,
+      created_date_utc: None,
+    }
+  }
+
+  fn domainlists(count: u32) -> IdentifiedList<DomainList> {
+    IdentifiedList::new(
+      (1..=count)
+        .map(|id| DomainList {
+          id: Some(id),
+          name: format!("list{}", id),
+          kind: ListKind::Dstdomain,
+          domains: vec![format!(".example{}.com", id)],
+          includes: vec![],
+          source: None,
+        })
+        .collect(),
+    )
+  }
+
+  /// The line in domains.conf that defines a list's acl from its file.
+  fn list_acl(id: u32, acl_type: &str) -> String {
+    format!(
+      "acl domains_{:0>4} {} \"{}/domains_{:0>4}.txt\"\n",
+      id, acl_type, LIST_DIR, id
+    )
+  }
+
+  fn generate_client(client: Client) -> Result<String> {
+    generate_client_with_quotas(client, &ExhaustedQuotas::new())
+  }
+
+  fn generate_client_with_quotas(client: Client, exhausted: &ExhaustedQuotas) -> Result<String> {
+    generate_client_impl(client, vec![], exhausted)
+  }
+
+  fn generate_client_with_global_leases(client: Client, leases: Vec<Lease>) -> Result<String> {
+    generate_client_impl(client, leases, &ExhaustedQuotas::new())
+  }
+
+  fn generate_client_impl(
+    client: Client,
+    global_leases: Vec<Lease>,
+    exhausted: &ExhaustedQuotas,
+  ) -> Result<String> {
+    let files = generate_squid_config(
+      LIST_DIR,
+      &IdentifiedList::new(vec![client]),
+      &domainlists(3),
+      &IdentifiedList::new(global_leases),
+      exhausted,
+      None,
+    )?;
+    Ok(files["client_0001.conf"].clone())
+  }
+
+  #[test]
+  fn deny_rules() -> Result<()> {
+    let conf = generate_client(client(
+      vec![rule(RuleKind::DenyHttpAccess, vec![1, 2])],
+      vec![],
+    ))?;
+
+    assert_eq!(
+      conf,
+      "acl client_0001 src 192.168.1.33\n\
+       http_access deny client_0001 domains_0001\n\
+       http_access deny client_0001 domains_0002\n"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn allowlist_rule() -> Result<()> {
+    let conf = generate_client(client(
+      vec![rule(RuleKind::AllowHttpAccess, vec![2])],
+      vec![],
+    ))?;
+
+    assert_eq!(
+      conf,
+      "acl client_0001 src 192.168.1.33\n\
+       http_access allow client_0001 domains_0002\n\
+       http_access deny client_0001 all\n"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn allowlist_widened_by_lease() -> Result<()> {
+    let conf = generate_client(client(
+      vec![
+        rule(RuleKind::DenyHttpAccess, vec![3]),
+        rule(RuleKind::AllowHttpAccess, vec![2]),
+      ],
+      vec![lease(rule(RuleKind::AllowHttpAccess, vec![1, 3]))],
+    ))?;
+
+    assert_eq!(
+      conf,
+      "acl client_0001 src 192.168.1.33\n\
+       http_access allow client_0001 domains_0002\n\
+       http_access allow client_0001 domains_0001\n\
+       http_access allow client_0001 domains_0003\n\
+       http_access deny client_0001 all\n"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn scheduled_deny_rule() -> Result<()> {
+    let mut games = rule(RuleKind::DenyHttpAccess, vec![2]);
+    games.schedule = Some(school_nights());
+    let conf = generate_client(client(
+      vec![rule(RuleKind::DenyHttpAccess, vec![1]), games],
+      vec![],
+    ))?;
+
+    assert_eq!(
+      conf,
+      "acl client_0001 src 192.168.1.33\n\
+       acl client_0001_rule_1_time time MTWHF 00:00-06:59\n\
+       acl client_0001_rule_1_time time SMTWH 19:00-23:59\n\
+       http_access deny client_0001 domains_0001\n\
+       http_access deny client_0001 client_0001_rule_1_time domains_0002\n"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn scheduled_allowlist() -> Result<()> {
+    let mut homework = rule(RuleKind::AllowHttpAccess, vec![3]);
+    homework.schedule = Some(school_nights());
+    let conf = generate_client(client(vec![homework], vec![]))?;
+
+    assert_eq!(
+      conf,
+      "acl client_0001 src 192.168.1.33\n\
+       acl client_0001_rule_0_time time MTWHF 00:00-06:59\n\
+       acl client_0001_rule_0_time time SMTWH 19:00-23:59\n\
+       http_access allow client_0001 client_0001_rule_0_time domains_0003\n\
+       http_access deny client_0001 client_0001_rule_0_time all\n"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn exhausted_quota() -> Result<()> {
+    let mut video = rule(RuleKind::QuotaHttpAccess, vec![2]);
+    video.daily_quota_minutes = Some(120);
+    let client = client(
+      vec![rule(RuleKind::QuotaHttpAccess, vec![1]), video],
+      vec![],
+    );
+
+    let conf = generate_client(client.clone())?;
+    assert_eq!(conf, "acl client_0001 src 192.168.1.33\n");
+
+    let conf = generate_client_with_quotas(client, &ExhaustedQuotas::from([(1, 1)]))?;
+    assert_eq!(
+      conf,
+      "acl client_0001 src 192.168.1.33\n\
+       http_access deny client_0001 domains_0002\n"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn future_lease() -> Result<()> {
+    let mut later = lease(rule(RuleKind::AllowHttpAccess, vec![1]));
+    later.start_date_utc = Some(Utc::now() + Duration::minutes(30));
+    let conf = generate_client(client(
+      vec![rule(RuleKind::DenyHttpAccess, vec![1])],
+      vec![later],
+    ))?;
+
+    assert_eq!(
+      conf,
+      "acl client_0001 src 192.168.1.33\n\
+       http_access deny client_0001 domains_0001\n"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn deny_leases() -> Result<()> {
+    let conf = generate_client(client(
+      vec![rule(RuleKind::DenyHttpAccess, vec![1])],
+      vec![
+        lease(rule(RuleKind::AllowHttpAccess, vec![1, 2])),
+        lease(rule(RuleKind::DenyHttpAccess, vec![2])),
+      ],
+    ))?;
+
+    assert_eq!(
+      conf,
+      "acl client_0001 src 192.168.1.33\n\
+       http_access deny client_0001 domains_0002\n"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn grounded() -> Result<()> {
+    let conf = generate_client(client(
+      vec![],
+      vec![lease(rule(RuleKind::DenyHttpAccess, vec![]))],
+    ))?;
+
+    assert_eq!(
+      conf,
+      "acl client_0001 src 192.168.1.33\n\
+       http_access deny client_0001 all\n"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn global_leases() -> Result<()> {
+    let conf = generate_client_with_global_leases(
+      client(vec![rule(RuleKind::DenyHttpAccess, vec![1, 2])], vec![]),
+      vec![lease(rule(RuleKind::AllowHttpAccess, vec![2]))],
+    )?;
+
+    assert_eq!(
+      conf,
+      "acl client_0001 src 192.168.1.33\n\
+       http_access deny client_0001 domains_0001\n"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn domain_leases() -> Result<()> {
+    let mut project = lease(rule(RuleKind::AllowHttpAccess, vec![]));
+    project.rule.domains = vec![".khanacademy.org".to_owned(), "scratch.mit.edu".to_owned()];
+    let mut distraction = lease(rule(RuleKind::DenyHttpAccess, vec![]));
+    distraction.rule.domains = vec![".roblox.com".to_owned()];
+
+    let conf = generate_client_with_global_leases(
+      client(vec![rule(RuleKind::DenyHttpAccess, vec![1])], vec![project]),
+      vec![distraction],
+    )?;
+
+    assert_eq!(
+      conf,
+      "acl client_0001 src 192.168.1.33\n\
+       acl client_0001_lease_0 dstdomain .khanacademy.org scratch.mit.edu\n\
+       acl client_0001_global_lease_0 dstdomain .roblox.com\n\
+       http_access deny client_0001 client_0001_global_lease_0\n\
+       http_access allow client_0001 client_0001_lease_0\n\
+       http_access deny client_0001 domains_0001\n"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn overlapping_domains() -> Result<()> {
+    let lists = IdentifiedList::new(vec![DomainList {
+      id: Some(1),
+      name: "video".to_owned(),
+      kind: ListKind::Dstdomain,
+      domains: vec![
+        "www.youtube.com".to_owned(),
+        ".youtube.com".to_owned(),
+        "vimeo.com".to_owned(),
+        "Vimeo.com".to_owned(),
+      ],
+      includes: vec![],
+      source: None,
+    }]);
+    let files = generate_squid_config(
+      LIST_DIR,
+      &IdentifiedList::new(vec![]),
+      &lists,
+      &IdentifiedList::new(vec![]),
+      &ExhaustedQuotas::new(),
+      None,
+    )?;
+
+    assert_eq!(files["domains_0001.txt"], ".youtube.com\nvimeo.com\n");
+
+    Ok(())
+  }
+
+  #[test]
+  fn regex_lists() -> Result<()> {
+    let lists = IdentifiedList::new(vec![
+      DomainList {
+        id: Some(1),
+        name: "games".to_owned(),
+        kind: ListKind::DstdomRegex,
+        domains: vec!["^cdn-games-.*\\.net$".to_owned()],
+        includes: vec![],
+        source: None,
+      },
+      DomainList {
+        id: Some(2),
+        name: "shorts".to_owned(),
+        kind: ListKind::UrlpathRegex,
+        domains: vec!["^/shorts/".to_owned()],
+        includes: vec![],
+        source: None,
+      },
+    ]);
+    let files = generate_squid_config(
+      LIST_DIR,
+      &IdentifiedList::new(vec![]),
+      &lists,
+      &IdentifiedList::new(vec![]),
+      &ExhaustedQuotas::new(),
+      None,
+    )?;
+
+    assert_eq!(
+      files["domains.conf"],
+      list_acl(1, "dstdom_regex -i") + &list_acl(2, "urlpath_regex -i")
+    );
+    assert_eq!(files["domains_0001.txt"], "^cdn-games-.*\\.net$\n");
+    assert_eq!(files["domains_0002.txt"], "^/shorts/\n");
+
+    Ok(())
+  }
+
+  #[test]
+  fn included_lists() -> Result<()> {
+    let mut lists = domainlists(3);
+    lists.items[2].includes = vec![1, 2];
+    let files = generate_squid_config(
+      LIST_DIR,
+      &IdentifiedList::new(vec![]),
+      &lists,
+      &IdentifiedList::new(vec![]),
+      &ExhaustedQuotas::new(),
+      None,
+    )?;
+
+    assert_eq!(files["domains_0002.txt"], ".example2.com\n");
+    assert_eq!(
+      files["domains_0003.txt"],
+      ".example3.com\n.example1.com\n.example2.com\n"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn exceptions() -> Result<()> {
+    let mut google = rule(RuleKind::DenyHttpAccess, vec![1]);
+    google.exception_domains = vec!["classroom.google.com".to_owned()];
+    google.exception_domainlists = vec![2];
+    let mut games = rule(RuleKind::QuotaHttpAccess, vec![3]);
+    games.daily_quota_minutes = Some(60);
+    games.exception_domains = vec!["scratch.mit.edu".to_owned()];
+
+    let conf = generate_client(client(vec![google, games], vec![]))?;
+
+    // The quota rule's exceptions only matter once the quota is used up.
+    assert_eq!(
+      conf,
+      "acl client_0001 src 192.168.1.33\n\
+       acl client_0001_rule_0_exceptions dstdomain classroom.google.com\n\
+       acl client_0001_rule_1_exceptions dstdomain scratch.mit.edu\n\
+       http_access allow client_0001 client_0001_rule_0_exceptions\n\
+       http_access allow client_0001 domains_0002\n\
+       http_access deny client_0001 domains_0001\n"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn content_rules() -> Result<()> {
+    let mut downloads = rule(RuleKind::DenyHttpAccess, vec![]);
+    downloads.file_extensions = vec!["exe".to_owned(), ".apk".to_owned()];
+    downloads.mime_types = vec![
+      "video/*".to_owned(),
+      "application/vnd.android.package-archive".to_owned(),
+    ];
+    downloads.schedule = Some(school_nights());
+    let mut project = lease(rule(RuleKind::AllowHttpAccess, vec![]));
+    project.rule.domains = vec!["scratch.mit.edu".to_owned()];
+
+    let conf = generate_client(client(
+      vec![rule(RuleKind::DenyHttpAccess, vec![1]), downloads],
+      vec![project],
+    ))?;
+
+    assert_eq!(
+      conf,
+      "acl client_0001 src 192.168.1.33\n\
+       acl client_0001_lease_0 dstdomain scratch.mit.edu\n\
+       acl client_0001_rule_1_mime_types rep_mime_type -i ^video/ ^application/vnd\\.android\\.package-archive($|;)\n\
+       acl client_0001_rule_1_file_extensions urlpath_regex -i \\.(exe|apk)(\\?.*)?$\n\
+       acl client_0001_rule_1_time time MTWHF 00:00-06:59\n\
+       acl client_0001_rule_1_time time SMTWH 19:00-23:59\n\
+       http_access deny client_0001 client_0001_rule_1_time client_0001_rule_1_file_extensions\n\
+       http_reply_access deny client_0001 client_0001_rule_1_time client_0001_rule_1_mime_types\n\
+       http_access allow client_0001 client_0001_lease_0\n\
+       http_access deny client_0001 domains_0001\n"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn bandwidth_limits() -> Result<()> {
+    let mut throttled = client(vec![rule(RuleKind::DenyHttpAccess, vec![1])], vec![]);
+    throttled.bandwidth = Some(BandwidthLimit {
+      rate_kbps: 800,
+      domainlists: vec![2, 3],
+    });
+    let mut tablet = client(vec![], vec![]);
+    tablet.id = Some(2);
+    tablet.ip = "192.168.1.34".to_owned();
+    tablet.bandwidth = Some(BandwidthLimit {
+      rate_kbps: 2000,
+      domainlists: vec![],
+    });
+
+    let files = generate_squid_config(
+      LIST_DIR,
+      &IdentifiedList::new(vec![throttled, tablet]),
+      &domainlists(3),
+      &IdentifiedList::new(vec![]),
+      &ExhaustedQuotas::new(),
+      None,
+    )?;
+
+    assert_eq!(files["delay_pools.conf"], "delay_pools 2\n");
+    assert_eq!(
+      files["client_0001.conf"],
+      "acl client_0001 src 192.168.1.33\n\
+       http_access deny client_0001 domains_0001\n\
+       delay_class 1 1\n\
+       delay_parameters 1 100000/100000\n\
+       delay_access 1 allow client_0001 domains_0002\n\
+       delay_access 1 allow client_0001 domains_0003\n\
+       delay_access 1 deny all\n"
+    );
+    assert_eq!(
+      files["client_0002.conf"],
+      "acl client_0002 src 192.168.1.34\n\
+       delay_class 2 1\n\
+       delay_parameters 2 250000/250000\n\
+       delay_access 2 allow client_0002\n\
+       delay_access 2 deny all\n"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn safe_search() -> Result<()> {
+    let mut laptop = client(vec![], vec![]);
+    laptop.safe_search = true;
+
+    let files = generate_squid_config(
+      LIST_DIR,
+      &IdentifiedList::new(vec![laptop]),
+      &domainlists(1),
+      &IdentifiedList::new(vec![]),
+      &ExhaustedQuotas::new(),
+      None,
+    )?;
+
+    assert_eq!(
+      files["domains.conf"],
+      list_acl(1, "dstdomain")
+        + "acl safe_search_connect method CONNECT\n\
+       acl safe_search_google dstdom_regex -i ^(www\\.)?google\\.[a-z]+(\\.[a-z]+)?$\n\
+       cache_peer forcesafesearch.google.com parent 443 0 no-query no-digest originserver name=safe_search_google\n\
+       acl safe_search_bing dstdomain www.bing.com\n\
+       cache_peer strict.bing.com parent 443 0 no-query no-digest originserver name=safe_search_bing\n\
+       acl safe_search_duckduckgo dstdomain duckduckgo.com www.duckduckgo.com\n\
+       cache_peer safe.duckduckgo.com parent 443 0 no-query no-digest originserver name=safe_search_duckduckgo\n\
+       acl safe_search_youtube dstdomain youtube.com www.youtube.com m.youtube.com youtubei.googleapis.com youtube.googleapis.com www.youtube-nocookie.com\n\
+       cache_peer restrict.youtube.com parent 443 0 no-query no-digest originserver name=safe_search_youtube\n"
+    );
+    assert_eq!(
+      files["client_0001.conf"],
+      "acl client_0001 src 192.168.1.33\n\
+       request_header_add YouTube-Restrict strict client_0001 safe_search_youtube\n\
+       cache_peer_access safe_search_google allow client_0001 safe_search_connect safe_search_google\n\
+       never_direct allow client_0001 safe_search_connect safe_search_google\n\
+       cache_peer_access safe_search_bing allow client_0001 safe_search_connect safe_search_bing\n\
+       never_direct allow client_0001 safe_search_connect safe_search_bing\n\
+       cache_peer_access safe_search_duckduckgo allow client_0001 safe_search_connect safe_search_duckduckgo\n\
+       never_direct allow client_0001 safe_search_connect safe_search_duckduckgo\n\
+       cache_peer_access safe_search_youtube allow client_0001 safe_search_connect safe_search_youtube\n\
+       never_direct allow client_0001 safe_search_connect safe_search_youtube\n"
+    );
+
+    // Google's country sites are covered with and without www, but not its other services.
+    let google = regex::Regex::new(
+      SAFE_SEARCH_SITES[0]
+        .acl
+        .strip_prefix("dstdom_regex -i ")
+        .unwrap(),
+    )?;
+    for host in [
+      "google.com",
+      "www.google.com",
+      "google.co.uk",
+      "www.google.de",
+    ] {
+      assert!(google.is_match(host), "{}", host);
+    }
+    for host in [
+      "maps.google.com",
+      "forcesafesearch.google.com",
+      "google.com.evil.example",
+    ] {
+      assert!(!google.is_match(host), "{}", host);
+    }
+
+    // Clients without safe search don't need the peers.
+    let files = generate_squid_config(
+      LIST_DIR,
+      &IdentifiedList::new(vec![client(vec![], vec![])]),
+      &domainlists(1),
+      &IdentifiedList::new(vec![]),
+      &ExhaustedQuotas::new(),
+      None,
+    )?;
+    assert_eq!(files["domains.conf"], list_acl(1, "dstdomain"));
+
+    Ok(())
+  }
+
+  #[test]
+  fn block_page() -> Result<()> {
+    let files = generate_squid_config(
+      LIST_DIR,
+      &IdentifiedList::new(vec![]),
+      &domainlists(2),
+      &IdentifiedList::new(vec![]),
+      &ExhaustedQuotas::new(),
+      Some("http://penguin.lan:8080/blocked"),
+    )?;
+    assert_eq!(
+      files["domains.conf"],
+      format!(
+        "{}deny_info http://penguin.lan:8080/blocked?list=1&ip=%i&url=%U domains_0001\n\
+         {}deny_info http://penguin.lan:8080/blocked?list=2&ip=%i&url=%U domains_0002\n\
+         acl block_page dstdomain penguin.lan\n\
+         acl block_page_port port 8080\n\
+         http_access allow block_page block_page_port\n\
+         acl blocked_all src all\n\
+         deny_info http://penguin.lan:8080/blocked?ip=%i&url=%U blocked_all\n",
+        list_acl(1, "dstdomain"),
+        list_acl(2, "dstdomain")
+      )
+    );
+    let url = "http://penguin.lan:8080/blocked?from=squid";
+    let files = generate_squid_config(
+      LIST_DIR,
+      &IdentifiedList::new(vec![]),
+      &IdentifiedList::new(vec![]),
+      &IdentifiedList::new(vec![]),
+      &ExhaustedQuotas::new(),
+      Some(url),
+    )?;
+    assert_eq!(
+      files["domains.conf"],
+      "# This file will be populated with penguin domains\n\
+       acl block_page dstdomain penguin.lan\n\
+       acl block_page_port port 8080\n\
+       http_access allow block_page block_page_port\n\
+       acl blocked_all src all\n\
+       deny_info http://penguin.lan:8080/blocked?from=squid&ip=%i&url=%U blocked_all\n"
+    );
+
+    // The block page can be on an address and the default port.
+    let files = generate_squid_config(
+      LIST_DIR,
+      &IdentifiedList::new(vec![]),
+      &IdentifiedList::new(vec![]),
+      &IdentifiedList::new(vec![]),
+      &ExhaustedQuotas::new(),
+      Some("http://192.168.1.1/blocked"),
+    )?;
+    assert!(files["domains.conf"].contains(
+      "acl block_page dst 192.168.1.1\n\
+       acl block_page_port port 80\n\
+       http_access allow block_page block_page_port\n"
+    ));
+
+    // Lines that don't end with a list end with an acl that has a deny_info of its own.
+    let mut downloads = rule(RuleKind::DenyHttpAccess, vec![]);
+    downloads.file_extensions = vec!["exe".to_owned()];
+    let mut lease_domains = rule(RuleKind::DenyHttpAccess, vec![]);
+    lease_domains.domains = vec!["example.com".to_owned()];
+    let files = generate_squid_config(
+      LIST_DIR,
+      &IdentifiedList::new(vec![client(
+        vec![downloads, rule(RuleKind::AllowHttpAccess, vec![1])],
+        vec![
+          lease(rule(RuleKind::DenyHttpAccess, vec![])),
+          lease(lease_domains),
+        ],
+      )]),
+      &domainlists(1),
+      &IdentifiedList::new(vec![]),
+      &ExhaustedQuotas::new(),
+      Some(url),
+    )?;
+    assert_eq!(
+      files["client_0001.conf"],
+      "acl client_0001 src 192.168.1.33\n\
+       acl client_0001_lease_1 dstdomain example.com\n\
+       deny_info http://penguin.lan:8080/blocked?from=squid&ip=%i&url=%U client_0001_lease_1\n\
+       acl client_0001_rule_0_file_extensions urlpath_regex -i \\.(exe)(\\?.*)?$\n\
+       deny_info http://penguin.lan:8080/blocked?from=squid&ip=%i&url=%U client_0001_rule_0_file_extensions\n\
+       http_access deny client_0001 blocked_all\n\
+       http_access deny client_0001 client_0001_lease_1\n\
+       http_access deny client_0001 client_0001_rule_0_file_extensions\n\
+       http_access allow client_0001 domains_0001\n\
+       http_access deny client_0001 blocked_all\n"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn hashes_files() -> Result<()> {
+    let dir = tempdir::TempDir::new("penguin-generate-test")?;
+    let files = generate_squid_config(
+      LIST_DIR,
+      &IdentifiedList::new(vec![client(
+        vec![rule(RuleKind::DenyHttpAccess, vec![1])],
+        vec![],
+      )]),
+      &domainlists(2),
+      &IdentifiedList::new(vec![]),
+      &ExhaustedQuotas::new(),
+      None,
+    )?;
+    assert!(hash_config_dir(dir.path())? != hash_config_files(&files));
+
+    write_config_files(dir.path(), &files)?;
+    let hashes = hash_config_files(&files);
+    assert_eq!(hash_config_dir(dir.path())?, hashes);
+    assert_eq!(
+      hashes["client_dummy.conf"],
+      "bc9d19d73af8817daa584795d187aec2fb0d2833b3a0ac876e9e7c8c6a8cd31e"
+    );
+
+    fs::write(dir.path().join("domains_0002.txt"), ".example3.com\n")?;
+    assert!(hash_config_dir(dir.path())? != hashes);
+
+    Ok(())
+  }
+
+  #[test]
+  fn diffs_files() -> Result<()> {
+    let dir = tempdir::TempDir::new("penguin-generate-test")?;
+    let old = ConfigFiles::from([
+      ("a.conf".to_owned(), "one\ntwo\n".to_owned()),
+      ("b.conf".to_owned(), "same\n".to_owned()),
+      ("c.conf".to_owned(), "gone\n".to_owned()),
+    ]);
+    write_config_files(dir.path(), &old)?;
+    fs::create_dir(dir.path().join("backup"))?;
+    fs::write(dir.path().join("core"), [0xff, 0xfe, 0x00])?;
+    assert_eq!(read_config_dir(dir.path())?, old);
+
+    let new = ConfigFiles::from([
+      ("a.conf".to_owned(), "one\nthree\n".to_owned()),
+      ("b.conf".to_owned(), "same\n".to_owned()),
+      ("d.conf".to_owned(), "new\n".to_owned()),
+    ]);
+    assert_eq!(
+      diff_config_files("squid", &old, &new),
+      "--- a/squid/a.conf\n\
+       +++ b/squid/a.conf\n\
+       @@ -1,2 +1,2 @@\n\
+       \x20one\n\
+       -two\n\
+       +three\n\
+       --- a/squid/c.conf\n\
+       +++ /dev/null\n\
+       @@ -1 +0,0 @@\n\
+       -gone\n\
+       --- /dev/null\n\
+       +++ b/squid/d.conf\n\
+       @@ -0,0 +1 @@\n\
+       +new\n"
+    );
+    assert_eq!(diff_config_files("squid", &old, &old), "");
+
+    Ok(())
+  }
+}
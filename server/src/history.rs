@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use chrono::serde::ts_milliseconds;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+use crate::model::{Conf, Lease, LeaseRecord, RuleKind};
+use crate::restlist::JsonRestList;
+
+/// A summary of the leases that ended in the week before a given time.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Summary {
+  #[serde(with = "ts_milliseconds")]
+  pub since_utc: DateTime<Utc>,
+  pub lease_count: u32,
+  pub revoked_count: u32,
+  /// The total time granted by leases that allowed access. Recurring leases aren't counted, since
+  /// they don't have a single start and end.
+  pub allowed_minutes: i64,
+  /// The total time taken away by leases that denied access, also excluding recurring leases.
+  pub denied_minutes: i64,
+  /// Allowed minutes broken down by who granted them.
+  pub allowed_minutes_by_granter: BTreeMap<String, i64>,
+}
+
+impl LeaseRecord {
+  /// Makes a record of a lease that ended at the given time, or when it was due to end if that was
+  /// earlier.
+  pub fn new(client_id: Option<u32>, lease: Lease, ended: DateTime<Utc>, revoked: bool) -> Self {
+    // A recurring lease's end date is only the end of its first occurrence.
+    let due = lease.end_date_utc.filter(|_| lease.recurrence.is_none());
+    LeaseRecord {
+      id: None,
+      client_id,
+      ended_date_utc: due.map_or(ended, |end| end.min(ended)),
+      lease,
+      revoked,
+    }
+  }
+
+  /// How long the lease was in effect for, if that's known.
+  fn duration(&self) -> Option<Duration> {
+    if self.lease.recurrence.is_some() {
+      return None;
+    }
+    let start = self.lease.start_date_utc.or(self.lease.created_date_utc)?;
+    Some((self.ended_date_utc - start).max(Duration::zero()))
+  }
+}
+
+pub fn load(conf: &Conf) -> Result<JsonRestList<LeaseRecord>> {
+  JsonRestList::<LeaseRecord>::load(conf.lease_history_json())
+}
+
+/// Adds records of leases that have ended to the history.
+pub fn archive(conf: &Conf, records: Vec<LeaseRecord>) -> Result<()> {
+  if records.is_empty() {
+    return Ok(());
+  }
+
+  let mut history = load(conf)?;
+  for record in records {
+    history.list.add(record);
+  }
+  history.save()
+}
+
+/// Summarises the records of leases that ended in the week before `now`.
+pub fn weekly_summary<'a, I>(records: I, now: DateTime<Utc>) -> Summary
+where
+  I: IntoIterator<Item = &'a LeaseRecord>,
+{
+  let since = now - Duration::days(7);
+  let mut summary = Summary {
+    since_utc: since,
+    lease_count: 0,
+    revoked_count: 0,
+    allowed_minutes: 0,
+    denied_minutes: 0,
+    allowed_minutes_by_granter: BTreeMap::new(),
+  };
+
+  for record in records
+    .into_iter()
+    .filter(|r| r.ended_date_utc > since && r.ended_date_utc <= now)
+  {
+    summary.lease_count += 1;
+    if record.revoked {
+      summary.revoked_count += 1;
+    }
+
+    let minutes = record.duration().map_or(0, |d| d.num_minutes());
+    match record.lease.rule.kind {
+      RuleKind::DenyHttpAccess => summary.denied_minutes += minutes,
+      _ => {
+        summary.allowed_minutes += minutes;
+        let granter = record
+          .lease
+          .granted_by
+          .clone()
+          .unwrap_or_else(|| "unknown".to_owned());
+        *summary
+          .allowed_minutes_by_granter
+          .entry(granter)
+          .or_default() += minutes;
+      }
+    }
+  }
+
+  summary
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::model::{Recurrence, Rule};
+  use chrono::TimeZone;
+
+  fn at(day: u32, hour: u32) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2024, 3, day, hour, 0, 0).unwrap()
+  }
+
+  #[allow(deprecated)]
+  fn lease(kind: RuleKind, start: DateTime<Utc>, end: DateTime<Utc>, by: &str) -> Lease {
+    Lease {
+      id: Some(1),
+      start_date_utc: None,
+      end_date_utc: Some(end),
+      end_date: None,
+      recurrence: None,
+      rule: Rule {
+        kind,
+        domainlists: vec![1],
+        domains: vec![],
+        exception_domains: vec![],
+        exception_domainlists: vec![],
+        mime_types: vec![],
+        file_extensions: vec![],
+        schedule: None,
+        daily_quota_minutes: None,
+      },
+      granted_by: Some(by.to_owned()),
+      created_date_utc: Some(start),
+    }
+  }
+
+  #[test]
+  fn records_revocation_time() {
+    let allow = lease(RuleKind::AllowHttpAccess, at(4, 10), at(4, 12), "mum");
+    assert_eq!(
+      LeaseRecord::new(Some(1), allow.clone(), at(4, 11), true).ended_date_utc,
+      at(4, 11)
+    );
+    assert_eq!(
+      LeaseRecord::new(Some(1), allow, at(4, 13), false).ended_date_utc,
+      at(4, 12)
+    );
+  }
+
+  #[test]
+  fn summarises_week() {
+    let mut weekly = lease(RuleKind::AllowHttpAccess, at(2, 10), at(2, 12), "dad");
+    weekly.start_date_utc = Some(at(2, 10));
+    weekly.recurrence = Some(Recurrence::Weekly);
+
+    let records = vec![
+      // Ended more than a week ago
+      LeaseRecord::new(
+        Some(1),
+        lease(RuleKind::AllowHttpAccess, at(1, 10), at(1, 12), "mum"),
+        at(1, 12),
+        false,
+      ),
+      LeaseRecord::new(
+        Some(1),
+        lease(RuleKind::AllowHttpAccess, at(4, 10), at(4, 12), "mum"),
+        at(4, 12),
+        false,
+      ),
+      LeaseRecord::new(
+        Some(1),
+        lease(RuleKind::AllowHttpAccess, at(5, 10), at(5, 12), "dad"),
+        at(5, 10) + Duration::minutes(30),
+        true,
+      ),
+      LeaseRecord::new(
+        Some(1),
+        lease(RuleKind::DenyHttpAccess, at(6, 18), at(6, 20), "mum"),
+        at(6, 20),
+        false,
+      ),
+      LeaseRecord::new(Some(1), weekly, at(7, 9), true),
+    ];
+
+    assert_eq!(
+      weekly_summary(&records, at(9, 9)),
+      Summary {
+        since_utc: at(2, 9),
+        lease_count: 4,
+        revoked_count: 2,
+        allowed_minutes: 150,
+        denied_minutes: 120,
+        allowed_minutes_by_granter: BTreeMap::from([
+          ("dad".to_owned(), 30),
+          ("mum".to_owned(), 120)
+        ]),
+      }
+    );
+  }
+}
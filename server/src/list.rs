@@ -1,6 +1,6 @@
 use std::mem;
 
-use crate::model::{Client, DomainList};
+use crate::model::{Client, DomainList, Lease, LeaseRecord};
 
 pub trait Identifiable {
   fn id(&self) -> Option<u32>;
@@ -16,7 +16,7 @@ impl<T: Identifiable + Clone> IdentifiedList<T> {
     Self { items }
   }
 
-  /// Finds the item with the given id and returns it if it exists.
+  // Finds the item with the given id and returns it if it exists.
   // pub fn get(&self, id: u32) -> Option<&T> {
   //   self.items.iter().find(|c| c.id() == Some(id))
   // }
@@ -74,3 +74,23 @@ impl Identifiable for DomainList {
     self.id = Some(id)
   }
 }
+
+impl Identifiable for Lease {
+  fn id(&self) -> Option<u32> {
+    self.id
+  }
+
+  fn set_id(&mut self, id: u32) {
+    self.id = Some(id)
+  }
+}
+
+impl Identifiable for LeaseRecord {
+  fn id(&self) -> Option<u32> {
+    self.id
+  }
+
+  fn set_id(&mut self, id: u32) {
+    self.id = Some(id)
+  }
+}
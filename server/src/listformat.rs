@@ -0,0 +1,211 @@
+use crate::model::{DomainList, ListFormat};
+
+/// Host names that appear in most hosts files but aren't things anyone wants to block.
+const HOSTS_BOILERPLATE: [&str; 6] = [
+  "localhost",
+  "localhost.localdomain",
+  "local",
+  "broadcasthost",
+  "ip6-localhost",
+  "ip6-loopback",
+];
+
+fn strip_comment(line: &str) -> &str {
+  line.split('#').next().unwrap_or_default().trim()
+}
+
+fn parse_hosts_line(line: &str) -> Vec<String> {
+  let mut tokens = strip_comment(line).split_whitespace();
+  // The first token is the address the hosts are mapped to.
+  if tokens.next().is_none() {
+    return Vec::new();
+  }
+  tokens
+    .filter(|t| !HOSTS_BOILERPLATE.contains(&t.to_lowercase().as_str()))
+    .map(|t| t.to_owned())
+    .collect()
+}
+
+fn parse_adblock_line(line: &str) -> Option<String> {
+  let line = line.trim();
+  // Rules with options only block some requests to the domain, e.g. "||example.com^$third-party",
+  // and anything else is a comment, an exception or a cosmetic filter.
+  let domain = line.strip_prefix("||")?.strip_suffix('^')?;
+  if domain.is_empty() || domain.contains(['/', '*', '$', '^']) {
+    return None;
+  }
+  Some(format!(".{}", domain))
+}
+
+fn parse_plain_line(line: &str) -> Option<String> {
+  strip_comment(line)
+    .split_whitespace()
+    .next()
+    .map(|t| t.to_owned())
+}
+
+fn parse_csv_line(line: &str) -> Option<String> {
+  let line = line.trim();
+  let field = match line.strip_prefix('"') {
+    // A quoted field ends at the next quote that isn't doubled.
+    Some(rest) => {
+      let mut field = String::new();
+      let mut chars = rest.chars().peekable();
+      while let Some(c) = chars.next() {
+        match c {
+          '"' if chars.peek() == Some(&'"') => {
+            field.push('"');
+            chars.next();
+          }
+          '"' => break,
+          c => field.push(c),
+        }
+      }
+      field
+    }
+    None => line.split(',').next().unwrap_or_default().to_owned(),
+  };
+
+  let field = field.trim();
+  // Skip blank lines and a header row.
+  if field.is_empty() || field.eq_ignore_ascii_case("domain") {
+    None
+  } else {
+    Some(field.to_owned())
+  }
+}
+
+fn csv_field(value: &str) -> String {
+  if value.contains([',', '"', '\n']) {
+    format!("\"{}\"", value.replace('"', "\"\""))
+  } else {
+    value.to_owned()
+  }
+}
+
+/// Extracts the entries from a list in the given format. The entries still need normalizing, since
+/// shared lists often contain mistakes.
+pub fn parse(format: ListFormat, text: &str) -> Vec<String> {
+  let lines = text.lines();
+  match format {
+    ListFormat::Hosts => lines.flat_map(parse_hosts_line).collect(),
+    ListFormat::Adblock => lines.filter_map(parse_adblock_line).collect(),
+    ListFormat::Plain => lines.filter_map(parse_plain_line).collect(),
+    ListFormat::Csv => lines.filter_map(parse_csv_line).collect(),
+  }
+}
+
+/// Writes a domain list in the given format. Hosts files can't block subdomains, so entries like
+/// ".example.com" only block "example.com" itself in a hosts file. Adblock rules always block
+/// subdomains, so "www.example.com" blocks its subdomains too.
+pub fn export(format: ListFormat, list: &DomainList) -> String {
+  let mut out = String::new();
+  match format {
+    ListFormat::Hosts => {
+      out.push_str(&format!("# {}\n", list.name));
+      for domain in list.domains.iter() {
+        out.push_str(&format!("0.0.0.0 {}\n", domain.trim_start_matches('.')));
+      }
+    }
+    ListFormat::Adblock => {
+      out.push_str(&format!("! Title: {}\n", list.name));
+      for domain in list.domains.iter() {
+        out.push_str(&format!("||{}^\n", domain.trim_start_matches('.')));
+      }
+    }
+    ListFormat::Plain => {
+      out.push_str(&format!("# {}\n", list.name));
+      for domain in list.domains.iter() {
+        out.push_str(&format!("{}\n", domain));
+      }
+    }
+    ListFormat::Csv => {
+      out.push_str("domain\n");
+      for domain in list.domains.iter() {
+        out.push_str(&format!("{}\n", csv_field(domain)));
+      }
+    }
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::model::ListKind;
+
+  #[test]
+  fn hosts() {
+    let text = "# Blocklist\n\
+                127.0.0.1 localhost\n\
+                0.0.0.0 casino.example  poker.example # gambling\n\
+                \n\
+                0.0.0.0 slots.example\n";
+    assert_eq!(
+      parse(ListFormat::Hosts, text),
+      vec!["casino.example", "poker.example", "slots.example"]
+    );
+  }
+
+  #[test]
+  fn adblock() {
+    let text = "[Adblock Plus 2.0]\n\
+                ! Title: Gambling\n\
+                ||casino.example^\n\
+                ||ads.example^$third-party\n\
+                @@||allowed.example^\n\
+                ##.banner\n\
+                ||poker.example/path^\n\
+                ||slots.example^\n";
+    assert_eq!(
+      parse(ListFormat::Adblock, text),
+      vec![".casino.example", ".slots.example"]
+    );
+  }
+
+  #[test]
+  fn plain() {
+    let text = "# Gambling\ncasino.example\n  poker.example  \n\n.slots.example # and subdomains\n";
+    assert_eq!(
+      parse(ListFormat::Plain, text),
+      vec!["casino.example", "poker.example", ".slots.example"]
+    );
+  }
+
+  #[test]
+  fn csv() {
+    let text = "domain,category\n\
+                casino.example,gambling\n\
+                \"poker.example\",gambling\n\
+                \n\
+                \"^a,b$\",regex\n";
+    assert_eq!(
+      parse(ListFormat::Csv, text),
+      vec!["casino.example", "poker.example", "^a,b$"]
+    );
+  }
+
+  #[test]
+  fn round_trips() {
+    let list = DomainList {
+      id: Some(1),
+      name: "gambling".to_owned(),
+      kind: ListKind::Dstdomain,
+      domains: vec![".casino.example".to_owned(), "poker.example".to_owned()],
+      includes: vec![],
+      source: None,
+    };
+
+    assert_eq!(
+      export(ListFormat::Hosts, &list),
+      "# gambling\n0.0.0.0 casino.example\n0.0.0.0 poker.example\n"
+    );
+    assert_eq!(
+      export(ListFormat::Adblock, &list),
+      "! Title: gambling\n||casino.example^\n||poker.example^\n"
+    );
+    for format in [ListFormat::Plain, ListFormat::Csv] {
+      assert_eq!(parse(format, &export(format, &list)), list.domains);
+    }
+  }
+}
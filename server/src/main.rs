@@ -1,17 +1,24 @@
 use std::{
-  path::Path,
+  collections::{BTreeMap, BTreeSet},
   sync::{Arc, Mutex},
   time::Duration,
 };
 
 use api::api_routes;
-use axum::{extract::State, routing::get, Router};
-use chrono::{Local, NaiveDateTime, Timelike, Utc};
+use axum::{
+  extract::{Query, State},
+  response::Html,
+  routing::get,
+  Router,
+};
+use chrono::{DateTime, Local, NaiveDateTime, Timelike, Utc};
+use list::IdentifiedList;
 use model::{Conf, DomainList};
+use quota::{exhausted_quotas, ExhaustedQuotas};
 use restlist::JsonRestList;
+use serde::Serialize;
 use serde_json::Value;
 use squid::ActiveState;
-use tempdir::TempDir;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio_schedule::{every, Job};
 use tower_http::{
@@ -22,20 +29,31 @@ use tracing::{error, warn, Level, info};
 use unifi::UnifiClient;
 
 use crate::{
-  file::{get_parent_or_die, read_json_value, write_json_value},
-  generate::generate_squid_config,
-  model::Client,
+  file::{read_json_value, write_json_value},
+  backend::{enabled_backends, Policy},
+  generate::ConfigHashes,
+  model::{Client, Lease, LeaseRecord},
 };
 
 mod api;
 mod auth;
+mod backend;
+mod blockpage;
+mod domain;
 mod errors;
 mod file;
 mod generate;
+mod history;
 mod list;
+mod listformat;
 mod model;
+mod nftables;
+mod quota;
 mod restlist;
+mod schedule;
 mod squid;
+mod subscription;
+mod unbound;
 mod unifi;
 
 const PORT: u32 = 8080;
@@ -55,35 +73,96 @@ async fn regenerate_config_handler(State(state): State<AppState>) -> errors::Res
   Ok("Done".to_owned())
 }
 
+/// The page squid sends clients to when a domain list blocks a request.
+async fn blocked_page_handler(
+  State(state): State<AppState>,
+  Query(blocked): Query<blockpage::Blocked>,
+) -> errors::Result<Html<String>> {
+  let domains = JsonRestList::<DomainList>::load(state.app_config.domains_json())?;
+  let clients = JsonRestList::<Client>::load(state.app_config.clients_json())?;
+  let global_leases = JsonRestList::<Lease>::load(state.app_config.leases_json())?;
+  let exhausted_quotas = state.gen_config_lock.lock().unwrap().exhausted_quotas.clone();
+  let reason = blockpage::reason(
+    &blocked,
+    &clients.list,
+    &domains.list,
+    &global_leases.list,
+    &exhausted_quotas,
+    Utc::now(),
+  );
+
+  let conf = &state.app_config.block_page;
+  let template = blockpage::load_template(conf)?;
+  Ok(Html(blockpage::render(&template, &conf.request_access_url, &reason)))
+}
+
 async fn regenerate_config(state: AppState) -> anyhow::Result<String> {
   let mut guard = state.gen_config_lock.lock().unwrap();
   let domains = JsonRestList::<DomainList>::load(state.app_config.domains_json())?;
   let clients = JsonRestList::<Client>::load(state.app_config.clients_json())?;
+  let global_leases = JsonRestList::<Lease>::load(state.app_config.leases_json())?;
 
-  let temp_dir = TempDir::new("penguin-squid")?;
-  std::fs::create_dir_all(&temp_dir)?;
-  generate_squid_config(&temp_dir, &clients.list, &domains.list)?;
+  // If the logs can't be read, keep enforcing whichever quotas were enforced before.
+  let exhausted_quotas = exhausted_quotas(&state.app_config, &clients.list, &domains.list)
+    .unwrap_or_else(|e| {
+      error!("Failed to work out quota usage: {:?}", e);
+      guard.exhausted_quotas.clone()
+    });
 
-  std::fs::create_dir_all(&state.app_config.squid_config_dir)?;
-  let dest_dir = std::fs::canonicalize(Path::new(&state.app_config.squid_config_dir))?;
-  let old_dir = get_parent_or_die(&dest_dir)?.join("squid_old");
+  let active_leases = active_leases(&clients.list, &global_leases.list, Utc::now());
+  let devices = nftables::load_devices(&state.app_config)?;
 
-  tracing::info!("Regenerating squid config to {:?}", dest_dir);
-  if old_dir.exists() {
-    std::fs::remove_dir_all(&old_dir)?;
-  }
-  if dest_dir.exists() {
-    std::fs::rename(&dest_dir, old_dir)?;
-  }
+  let policy = Policy {
+    clients: &clients.list,
+    domainlists: &domains.list,
+    global_leases: &global_leases.list,
+    exhausted_quotas: &exhausted_quotas,
+    devices: &devices,
+  };
 
-  std::fs::rename(temp_dir, &dest_dir)?;
-  *guard += 1;
-  tracing::info!("Wrote squid configuration. Generation={}", *guard);
+  // Each backend is applied on its own, so one that fails doesn't stop the others being updated.
+  let mut changed = false;
+  let mut errors = Vec::new();
+  for backend in enabled_backends(&state.app_config) {
+    match backend::apply(backend.as_ref(), &policy) {
+      Ok(Some(hashes)) => {
+        guard.hashes.insert(backend.name().to_owned(), hashes);
+        changed = true;
+      }
+      // The first time round, daemons that forget their configuration need it loaded even though
+      // the files haven't changed.
+      Ok(None) if !backend.persistent() && !guard.hashes.contains_key(backend.name()) => {
+        match backend::reload_installed(backend.as_ref()) {
+          Ok(hashes) => {
+            guard.hashes.insert(backend.name().to_owned(), hashes);
+          }
+          Err(e) => errors.push((backend.name(), e)),
+        }
+      }
+      Ok(None) => {}
+      Err(e) => errors.push((backend.name(), e)),
+    }
+  }
 
-  if state.app_config.hup_squid_daemon {
-    squid::reload_config();
+  if changed {
+    guard.number += 1;
+    tracing::info!("Wrote configuration. Generation={}", guard.number);
   }
+  if !errors.is_empty() {
+    let message: Vec<_> = errors
+      .iter()
+      .map(|(name, e)| format!("{}: {}", name, e))
+      .collect();
+    guard.error = Some(message.join("\n"));
+    return Err(anyhow::anyhow!(message.join("\n")));
+  }
+  guard.error = None;
+  guard.exhausted_quotas = exhausted_quotas;
+  guard.active_leases = active_leases;
 
+  if !changed {
+    return Ok("Unchanged".to_owned());
+  }
   Ok("Done".to_owned())
 }
 
@@ -91,24 +170,63 @@ async fn possibly_regenerate_config(state: AppState) -> anyhow::Result<String> {
   // TODO: check lastmod time of the files and skip loading if not changed.
   let mut clients = JsonRestList::<Client>::load(state.app_config.clients_json())?;
 
+  // Expired leases are moved into the lease history, so there's a record of them.
   let now = Utc::now();
-  let mut lease_found: bool = false;
+  let mut expired = Vec::new();
   for client in clients.list.items.iter_mut() {
-    let old_len = client.leases.len();
-    client.leases.retain(|l| now <= l.end_date_utc.unwrap());
-    let new_len = client.leases.len();
-    if new_len != old_len {
-      lease_found = true;
-    }
+    let (ended, current) = client.leases.drain(..).partition(|l| l.is_expired(now));
+    client.leases = current;
+    expired.extend(
+      ended
+        .into_iter()
+        .map(|l: Lease| LeaseRecord::new(client.id, l, now, false)),
+    );
   }
-
+  let lease_found = !expired.is_empty();
+
+  let mut global_leases = JsonRestList::<Lease>::load(state.app_config.leases_json())?;
+  let (ended, current) = global_leases
+    .list
+    .items
+    .drain(..)
+    .partition(|l| l.is_expired(now));
+  global_leases.list.items = current;
+  let global_lease_found = !ended.is_empty();
+  expired.extend(
+    ended
+      .into_iter()
+      .map(|l: Lease| LeaseRecord::new(None, l, now, false)),
+  );
+
+  history::archive(&state.app_config, expired)?;
   if lease_found {
     // Save the clients config back out with the expired leases removed.
     clients.save()?;
+  }
+  if global_lease_found {
+    global_leases.save()?;
+  }
+
+  if lease_found || global_lease_found {
     tracing::info!("Regenerating due to expired leases");
     return regenerate_config(state).await;
   }
 
+  // Leases that are scheduled to start in the future, or recur, need the config regenerating when
+  // they start and end.
+  let active = active_leases(&clients.list, &global_leases.list, now);
+  if active != state.gen_config_lock.lock().unwrap().active_leases {
+    tracing::info!("Regenerating due to leases starting or ending");
+    return regenerate_config(state).await;
+  }
+
+  let domains = JsonRestList::<DomainList>::load(state.app_config.domains_json())?;
+  let exhausted = exhausted_quotas(&state.app_config, &clients.list, &domains.list)?;
+  if exhausted != state.gen_config_lock.lock().unwrap().exhausted_quotas {
+    tracing::info!("Regenerating due to quota changes");
+    return regenerate_config(state).await;
+  }
+
   Ok("Done".to_owned())
 }
 
@@ -125,11 +243,77 @@ async fn listen_for_events(state: AppState, mut rx: Receiver<Event>) {
   }
 }
 
+/// The state of the most recently generated squid configuration.
+#[derive(Default)]
+pub struct Generation {
+  number: u32,
+  // The quota rules that were being enforced
+  exhausted_quotas: ExhaustedQuotas,
+  // The leases that were in effect, as pairs of client id (or None for global leases) and lease
+  // index
+  active_leases: BTreeSet<(Option<u32>, usize)>,
+  // Why the most recent attempt to generate a configuration failed, if it did
+  error: Option<String>,
+  // The hash of each file in each backend's configuration, by backend name
+  hashes: BTreeMap<String, ConfigHashes>,
+}
+
+/// What the API reports about the generated squid configuration.
+#[derive(Serialize)]
+pub struct GenerationStatus {
+  pub number: u32,
+  pub error: Option<String>,
+  pub hashes: BTreeMap<String, ConfigHashes>,
+}
+
+impl Generation {
+  pub fn status(&self) -> GenerationStatus {
+    GenerationStatus {
+      number: self.number,
+      error: self.error.clone(),
+      hashes: self.hashes.clone(),
+    }
+  }
+}
+
+/// Puts the previous configuration of every backend back in place, and makes the daemons reload it.
+pub fn rollback_config(state: &AppState) -> anyhow::Result<GenerationStatus> {
+  let mut guard = state.gen_config_lock.lock().unwrap();
+  for backend in enabled_backends(&state.app_config) {
+    let hashes = backend::rollback(backend.as_ref())?;
+    guard.hashes.insert(backend.name().to_owned(), hashes);
+  }
+
+  Ok(guard.status())
+}
+
+fn active_leases(
+  clients: &IdentifiedList<Client>,
+  global_leases: &IdentifiedList<Lease>,
+  now: DateTime<Utc>,
+) -> BTreeSet<(Option<u32>, usize)> {
+  let mut result = BTreeSet::new();
+  for client in clients.items.iter() {
+    for (index, lease) in client.leases.iter().enumerate() {
+      if lease.is_active(now) {
+        result.insert((client.id, index));
+      }
+    }
+  }
+  for (index, lease) in global_leases.items.iter().enumerate() {
+    if lease.is_active(now) {
+      result.insert((None, index));
+    }
+  }
+
+  result
+}
+
 #[derive(Clone)]
 pub struct AppState {
   events: Sender<Event>,
   // A mutex on the generated squid configuration
-  gen_config_lock: Arc<Mutex<u32>>,
+  gen_config_lock: Arc<Mutex<Generation>>,
   // A mutex on the local configuration json files
   //    config_lock: Arc<Mutex<u32>>,
 
@@ -160,7 +344,7 @@ async fn main() {
 
   let state = AppState {
     events: tx,
-    gen_config_lock: Arc::new(Mutex::new(0)),
+    gen_config_lock: Arc::new(Mutex::new(Generation::default())),
     //       config_lock: Arc::new(Mutex::new(0)),
     app_config: Conf::load().unwrap(),
     unifi_client: Arc::new(tokio::sync::Mutex::new(None))
@@ -169,6 +353,9 @@ async fn main() {
   if let Err(e) = repair_client_json(&state).await {
     error!("Failed to repair client json: {:?}", e);
   }
+  if let Err(e) = assign_lease_ids(&state) {
+    error!("Failed to assign lease ids: {:?}", e);
+  }
 
   let state_for_listen = state.clone();
   tokio::spawn(async move { listen_for_events(state_for_listen, rx).await });
@@ -196,6 +383,21 @@ async fn main() {
       .await;
   });
 
+  // Every minute, refresh any domain lists that are kept up to date from an external source.
+  let state_for_subscriptions = state.clone();
+  tokio::spawn(async move {
+    every(60)
+      .seconds()
+      .perform(|| async {
+        match subscription::refresh_due(&state_for_subscriptions.app_config).await {
+          Ok(true) => state_for_subscriptions.regenerate().await,
+          Ok(false) => {}
+          Err(err) => tracing::error!("Failed to refresh domain lists: {:?}", err),
+        }
+      })
+      .await;
+  });
+
   if state.app_config.unifi.enabled {
     if state.app_config.unifi.username.is_none() || state.app_config.unifi.password.is_none() {
       error!("Support for Unifi is enabled in config, but username and password are not specified. Ignoring.");
@@ -230,6 +432,7 @@ async fn main() {
   let app = Router::new()
     .route("/statusz", get(status))
     .route("/generate", get(regenerate_config_handler))
+    .route("/blocked", get(blocked_page_handler))
     .nest("/api", api_routes())
     .with_state(state)
     .layer(cors)
@@ -300,7 +503,7 @@ async fn poll_statusz() {
           tracing::info!("Server listening on port {}", PORT);
           #[cfg(target_os = "linux")]
           {
-            libsystemd::daemon::notify(false, &[libsystemd::daemon::NotifyState::Ready]);
+            let _ = libsystemd::daemon::notify(false, &[libsystemd::daemon::NotifyState::Ready]);
           }
           return;
         }
@@ -353,3 +556,23 @@ async fn repair_client_json(state: &AppState) -> anyhow::Result<()> {
 
   Ok(())
 }
+
+/// Leases saved before leases had ids need one so that they can be managed individually.
+fn assign_lease_ids(state: &AppState) -> anyhow::Result<()> {
+  let mut clients = JsonRestList::<Client>::load(state.app_config.clients_json())?;
+  let missing = clients
+    .list
+    .items
+    .iter()
+    .flat_map(|c| c.leases.iter())
+    .any(|l| l.id.is_none());
+
+  if missing {
+    for client in clients.list.items.iter_mut() {
+      client.assign_lease_ids();
+    }
+    clients.save()?;
+  }
+
+  Ok(())
+}
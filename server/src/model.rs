@@ -6,7 +6,7 @@ use std::io::BufReader;
 use std::path::PathBuf;
 
 use chrono::serde::{ts_milliseconds, ts_milliseconds_option};
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Duration, Local, NaiveDateTime, TimeZone, Utc};
 use confique::{Config, Builder};
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
@@ -22,15 +22,52 @@ pub struct Client {
   #[serde(skip_serializing_if = "Vec::is_empty", default)]
   pub leases: Vec<Lease>,
   #[serde(skip_serializing_if = "Option::is_none")]
-  pub mac_address: Option<String>
+  pub mac_address: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none", default)]
+  pub bandwidth: Option<BandwidthLimit>,
+  /// Forces SafeSearch on Google, Bing and DuckDuckGo, and restricted mode on YouTube.
+  #[serde(default)]
+  pub safe_search: bool,
+}
+
+/// Throttles a client's downloads, using a squid delay pool.
+#[derive(Serialize, Deserialize, Clone, TS)]
+pub struct BandwidthLimit {
+  /// The most the client can download at, in kilobits per second.
+  pub rate_kbps: u32,
+  /// The domain lists the limit applies to. If there are none, it applies to everything.
+  #[serde(skip_serializing_if = "Vec::is_empty", default)]
+  pub domainlists: Vec<u32>,
+}
+
+impl BandwidthLimit {
+  /// The rate in bytes per second, which is what squid expects.
+  pub fn bytes_per_second(&self) -> u64 {
+    u64::from(self.rate_kbps) * 1000 / 8
+  }
+}
+
+impl Client {
+  /// Gives an id to any of the client's leases that don't have one yet.
+  pub fn assign_lease_ids(&mut self) {
+    let next_id = self.leases.iter().filter_map(|l| l.id).max().unwrap_or(0) + 1;
+    let missing = self.leases.iter_mut().filter(|l| l.id.is_none());
+    for (id, lease) in (next_id..).zip(missing) {
+      lease.id = Some(id);
+    }
+  }
 }
 
 #[derive(Copy, Clone, TS, Serialize, Deserialize, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 pub enum RuleKind {
   #[serde(rename = "allow_http_access")]
   AllowHttpAccess,
   #[serde(rename = "deny_http_access")]
-  DenyHttpAccess
+  DenyHttpAccess,
+  /// Denies access once the client has used up its daily quota of time on the domains.
+  #[serde(rename = "quota_http_access")]
+  QuotaHttpAccess,
 }
 
 #[derive(Serialize, Deserialize, Clone, TS)]
@@ -39,16 +76,197 @@ pub struct Rule {
   pub kind: RuleKind,
   #[serde(skip_serializing_if = "Vec::is_empty", default)]
   pub domainlists: Vec<u32>,
+  /// Individual domains the rule applies to, in addition to its domain lists. Only leases can list
+  /// domains directly.
+  #[serde(skip_serializing_if = "Vec::is_empty", default)]
+  pub domains: Vec<String>,
+  /// Domains that are allowed even though the rule denies a list containing them, e.g.
+  /// "classroom.google.com" when ".google.com" is denied. Only deny and quota rules can have
+  /// exceptions.
+  #[serde(skip_serializing_if = "Vec::is_empty", default)]
+  pub exception_domains: Vec<String>,
+  /// Domain lists that are allowed even though the rule denies a list containing them.
+  #[serde(skip_serializing_if = "Vec::is_empty", default)]
+  pub exception_domainlists: Vec<u32>,
+  /// Content types of replies to deny, whichever site they come from, e.g. "video/mp4" or
+  /// "video/*". Only deny rules can have content types.
+  #[serde(skip_serializing_if = "Vec::is_empty", default)]
+  pub mime_types: Vec<String>,
+  /// Extensions of files to deny downloading, whichever site they come from, e.g. "exe". Only deny
+  /// rules can have file extensions.
+  #[serde(skip_serializing_if = "Vec::is_empty", default)]
+  pub file_extensions: Vec<String>,
+  /// If present, the rule only applies at the scheduled times. Otherwise it always applies.
+  #[serde(skip_serializing_if = "Option::is_none", default)]
+  pub schedule: Option<Schedule>,
+  /// For quota rules, the number of minutes a day the client may spend on the domains.
+  #[serde(skip_serializing_if = "Option::is_none", default)]
+  pub daily_quota_minutes: Option<u32>,
+}
+
+#[derive(Copy, Clone, TS, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Day {
+  Sun,
+  Mon,
+  Tue,
+  Wed,
+  Thu,
+  Fri,
+  Sat,
+}
+
+/// A range of local times, in "HH:MM" format. If end is not after start, the range crosses
+/// midnight into the following day.
+#[derive(Serialize, Deserialize, Clone, TS)]
+pub struct TimeRange {
+  pub start: String,
+  pub end: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, TS)]
+pub struct Schedule {
+  /// The days on which the schedule starts. Empty means every day.
+  #[serde(skip_serializing_if = "Vec::is_empty", default)]
+  pub days: Vec<Day>,
+  /// The times of day the schedule is active. Empty means all day.
+  #[serde(skip_serializing_if = "Vec::is_empty", default)]
+  pub times: Vec<TimeRange>,
+  /// The IANA timezone (e.g. "America/Los_Angeles") that days and times are in. Defaults to the
+  /// server's local timezone.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub timezone: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, TS)]
 //#[ts(export)]
 pub struct Lease {
+  #[serde(skip_serializing_if = "Option::is_none", default)]
+  pub id: Option<u32>,
+  /// When the lease starts. If absent, the lease starts immediately.
+  #[serde(with = "ts_milliseconds_option", skip_serializing_if = "Option::is_none", default)]
+  pub start_date_utc: Option<DateTime<Utc>>,
   #[serde(with = "ts_milliseconds_option")]
   pub end_date_utc: Option<DateTime<Utc>>,
   #[deprecated(note = "Use end_date_utc")]
   pub end_date: Option<NaiveDateTime>,
+  /// If present, the lease repeats from start_date_utc to end_date_utc every day or week, and never
+  /// expires.
+  #[serde(skip_serializing_if = "Option::is_none", default)]
+  pub recurrence: Option<Recurrence>,
   pub rule: Rule,
+  /// The email address of the user who granted the lease, if known.
+  #[serde(skip_serializing_if = "Option::is_none", default)]
+  pub granted_by: Option<String>,
+  /// When the lease was granted.
+  #[serde(with = "ts_milliseconds_option", skip_serializing_if = "Option::is_none", default)]
+  pub created_date_utc: Option<DateTime<Utc>>,
+}
+
+/// A lease that has ended, either by expiring or by being revoked, kept so there's a record of how
+/// much extra time was granted.
+#[derive(Serialize, Deserialize, Clone, TS)]
+//#[ts(export)]
+pub struct LeaseRecord {
+  #[serde(skip_serializing_if = "Option::is_none", default)]
+  pub id: Option<u32>,
+  /// The client the lease belonged to, or None if it applied to all clients.
+  pub client_id: Option<u32>,
+  pub lease: Lease,
+  /// When the lease ended. For a revoked lease, this is when it was revoked.
+  #[serde(with = "ts_milliseconds")]
+  pub ended_date_utc: DateTime<Utc>,
+  /// Whether the lease was revoked rather than being left to expire.
+  pub revoked: bool,
+}
+
+#[derive(Copy, Clone, TS, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Recurrence {
+  Daily,
+  Weekly,
+}
+
+impl Recurrence {
+  pub fn days(&self) -> i64 {
+    match self {
+      Recurrence::Daily => 1,
+      Recurrence::Weekly => 7,
+    }
+  }
+}
+
+impl Lease {
+  /// Whether the lease is in effect at the given time.
+  pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+    self.occurrence_at(now, &Local).is_some()
+  }
+
+  /// Returns the start and end of the occurrence of the lease that is in effect at the given time,
+  /// if any. Recurring leases repeat at the same local time in the given timezone, even across
+  /// daylight savings changes.
+  fn occurrence_at<T: TimeZone>(
+    &self,
+    now: DateTime<Utc>,
+    tz: &T,
+  ) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let end = self.end_date_utc?;
+
+    match (self.start_date_utc, self.recurrence) {
+      (Some(start), Some(recurrence)) => {
+        if now < start {
+          return None;
+        }
+
+        let duration = end - start;
+        let first = start.with_timezone(tz).naive_local();
+        let days = (now.with_timezone(tz).date_naive() - first.date()).num_days();
+        let latest = days - days.rem_euclid(recurrence.days());
+
+        // The occurrence that started most recently might have begun in an earlier cycle if it
+        // runs past midnight.
+        [latest, latest - recurrence.days()]
+          .into_iter()
+          .filter(|cycle| *cycle >= 0)
+          .filter_map(|cycle| {
+            tz.from_local_datetime(&(first + Duration::days(cycle)))
+              .earliest()
+          })
+          .map(|occurrence| occurrence.with_timezone(&Utc))
+          .map(|occurrence| (occurrence, occurrence + duration))
+          .find(|(start, end)| *start <= now && now < *end)
+      }
+      (start, _) => {
+        if start.is_none_or(|start| start <= now) && now < end {
+          Some((start.unwrap_or(now), end))
+        } else {
+          None
+        }
+      }
+    }
+  }
+
+  /// Whether the lease is over and will never be active again.
+  pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+    if self.recurrence.is_some() && self.start_date_utc.is_some() {
+      return false;
+    }
+
+    self.end_date_utc.is_none_or(|end| now > end)
+  }
+
+  /// How much longer the lease will be in effect for. For leases that haven't started yet, this is
+  /// how long they'll last once they do.
+  pub fn remaining(&self, now: DateTime<Utc>) -> Duration {
+    if let Some((_, end)) = self.occurrence_at(now, &Local) {
+      return end - now;
+    }
+
+    match self.end_date_utc {
+      Some(end) if !self.is_expired(now) => end - self.start_date_utc.unwrap_or(end),
+      _ => Duration::zero(),
+    }
+  }
 }
 
 #[derive(Serialize, Deserialize, Clone, TS)]
@@ -56,8 +274,89 @@ pub struct Lease {
 pub struct DomainList {
   pub id: Option<u32>,
   pub name: String,
+  /// How the entries in `domains` are matched against requests.
+  #[serde(skip_serializing_if = "ListKind::is_dstdomain", default)]
+  pub kind: ListKind,
   #[serde(skip_serializing_if = "Vec::is_empty", default)]
   pub domains: Vec<String>,
+  /// The ids of other lists of the same kind whose domains are part of this list too.
+  #[serde(skip_serializing_if = "Vec::is_empty", default)]
+  pub includes: Vec<u32>,
+  /// If present, the list's domains are kept up to date from an external source.
+  #[serde(skip_serializing_if = "Option::is_none", default)]
+  pub source: Option<ListSource>,
+}
+
+/// Somewhere a domain list is periodically fetched from, along with the outcome of the last fetch.
+#[derive(Serialize, Deserialize, Clone, TS, Debug, PartialEq)]
+pub struct ListSource {
+  /// An http or https url, or the path of a local file.
+  pub location: String,
+  pub format: ListFormat,
+  /// How often to check the source for changes.
+  #[serde(default = "default_refresh_minutes")]
+  pub refresh_minutes: u32,
+  /// The ETag of the last response, so unchanged lists aren't downloaded again.
+  #[serde(skip_serializing_if = "Option::is_none", default)]
+  pub etag: Option<String>,
+  #[serde(with = "ts_milliseconds_option", skip_serializing_if = "Option::is_none", default)]
+  pub last_checked_utc: Option<DateTime<Utc>>,
+  /// When the list's domains last changed as a result of fetching the source.
+  #[serde(with = "ts_milliseconds_option", skip_serializing_if = "Option::is_none", default)]
+  pub last_updated_utc: Option<DateTime<Utc>>,
+  /// Why the last fetch failed, if it did.
+  #[serde(skip_serializing_if = "Option::is_none", default)]
+  pub last_error: Option<String>,
+}
+
+fn default_refresh_minutes() -> u32 {
+  24 * 60
+}
+
+/// Formats that lists of domains are commonly shared in.
+#[derive(Serialize, Deserialize, Clone, Copy, TS, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ListFormat {
+  /// A hosts file, e.g. "0.0.0.0 example.com".
+  Hosts,
+  /// Adblock filter rules, e.g. "||example.com^". Only rules that block whole domains are used.
+  Adblock,
+  /// One domain per line.
+  #[serde(alias = "text")]
+  Plain,
+  /// Comma separated values, with the domain in the first column.
+  Csv,
+}
+
+/// The kinds of domain list, named after the squid acl types they become.
+#[derive(Serialize, Deserialize, Clone, Copy, TS, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ListKind {
+  /// Domains, where a leading "." also matches subdomains.
+  #[default]
+  Dstdomain,
+  /// Regular expressions matched against the host name.
+  DstdomRegex,
+  /// Regular expressions matched against the whole url.
+  UrlRegex,
+  /// Regular expressions matched against the url path and query.
+  UrlpathRegex,
+}
+
+impl ListKind {
+  pub fn is_dstdomain(&self) -> bool {
+    *self == ListKind::Dstdomain
+  }
+
+  /// The squid acl type, along with any flags.
+  pub fn acl_type(&self) -> &'static str {
+    match self {
+      ListKind::Dstdomain => "dstdomain",
+      ListKind::DstdomRegex => "dstdom_regex -i",
+      ListKind::UrlRegex => "url_regex -i",
+      ListKind::UrlpathRegex => "urlpath_regex -i",
+    }
+  }
 }
 
 #[derive(Serialize, Deserialize, Clone, TS, Debug)]
@@ -100,6 +399,57 @@ impl Default for UnifiConfig {
   }
 }
 
+/// The daemons that can enforce clients' rules.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+  Squid,
+  Unbound,
+  Nftables,
+}
+
+#[derive(Config, Clone, Debug)]
+pub struct UnboundConfig {
+  #[config(default = "unbound")]
+  pub config_dir: String,
+  #[config(default = false)]
+  pub reload_daemon: bool,
+  /// Whether to check generated configuration with `unbound-checkconf` before putting it in place.
+  #[config(default = false)]
+  pub validate_config: bool,
+  #[config(default = "/usr/sbin/unbound-checkconf")]
+  pub checkconf_binary: String,
+}
+
+#[derive(Config, Clone, Debug)]
+pub struct NftablesConfig {
+  #[config(default = "nftables")]
+  pub config_dir: String,
+  /// Whether to load the generated ruleset into the kernel. Needs CAP_NET_ADMIN.
+  #[config(default = false)]
+  pub load_ruleset: bool,
+  /// Whether to check the generated ruleset with `nft -c` before putting it in place.
+  #[config(default = false)]
+  pub validate_config: bool,
+  #[config(default = "/usr/sbin/nft")]
+  pub nft_binary: String,
+}
+
+#[derive(Config, Clone, Debug)]
+pub struct BlockPageConfig {
+  /// Whether squid sends requests that a domain list denies to penguin's block page.
+  #[config(default = false)]
+  pub enabled: bool,
+  /// Where clients reach the block page. Squid adds the list, the client's address and the url.
+  #[config(default = "http://penguin.lan:8080/blocked")]
+  pub url: String,
+  /// An html file to render the block page from instead of the built in one.
+  pub template: Option<String>,
+  /// The link for asking for access. Placeholders in it are filled in url encoded.
+  #[config(default = "mailto:?subject=Please%20unblock%20{{url}}&body={{client}}%20was%20blocked%20by%20{{rule}}%20({{list}}).")]
+  pub request_access_url: String,
+}
+
 // App wide configuration
 #[derive(Config, Clone, Debug)]
 pub struct Conf {
@@ -111,6 +461,15 @@ pub struct Conf {
   pub squid_log_dir: String,
   #[config(default = false)]
   pub hup_squid_daemon: bool,
+  /// Whether to check generated configuration with `squid -k parse` before putting it in place.
+  #[config(default = false)]
+  pub validate_squid_config: bool,
+  /// The squid binary used to check generated configuration.
+  #[config(default = "/usr/sbin/squid")]
+  pub squid_binary: String,
+  /// The local time ("HH:MM") at which daily quotas reset.
+  #[config(default = "00:00")]
+  pub quota_reset_time: String,
 
   #[config(default = false)]
   pub require_auth: bool,
@@ -120,6 +479,19 @@ pub struct Conf {
 
   #[config(nested)]
   pub unifi: UnifiConfig,
+
+  /// The daemons that enforce clients' rules. Any combination can be used together.
+  #[config(default = ["squid"])]
+  pub backends: Vec<BackendKind>,
+
+  #[config(nested)]
+  pub unbound: UnboundConfig,
+
+  #[config(nested)]
+  pub nftables: NftablesConfig,
+
+  #[config(nested)]
+  pub block_page: BlockPageConfig,
 }
 
 impl Conf {
@@ -164,10 +536,24 @@ impl Conf {
     self.config_path().join("domains.json")
   }
 
+  pub fn leases_json(&self) -> PathBuf {
+    self.config_path().join("leases.json")
+  }
+
+  pub fn lease_history_json(&self) -> PathBuf {
+    self.config_path().join("lease_history.json")
+  }
+
   pub fn netaccess_json(&self) -> PathBuf {
     self.config_path().join("netaccess.json")
   }
 
+  /// Internet access for devices, when it's turned on and off by the nftables backend instead of
+  /// a UniFi gateway.
+  pub fn local_netaccess_json(&self) -> PathBuf {
+    self.config_path().join("netaccess_devices.json")
+  }
+
   pub fn load_netaccess_config(&self) -> Result<HashMap<String, NetAccessConfig>> {
     let path = self.netaccess_json();
     let items = if path.exists() {
@@ -191,3 +577,80 @@ impl Conf {
   // }
 
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[allow(deprecated)]
+  fn lease(start: Option<DateTime<Utc>>, end: DateTime<Utc>, recurrence: Option<Recurrence>) -> Lease {
+    Lease {
+      id: None,
+      start_date_utc: start,
+      end_date_utc: Some(end),
+      end_date: None,
+      recurrence,
+      rule: Rule {
+        kind: RuleKind::AllowHttpAccess,
+        domainlists: vec![1],
+        domains: vec![],
+        exception_domains: vec![],
+        exception_domainlists: vec![],
+        mime_types: vec![],
+        file_extensions: vec![],
+        schedule: None,
+        daily_quota_minutes: None,
+      },
+      granted_by: None,
+      created_date_utc: None,
+    }
+  }
+
+  fn at(day: u32, hour: u32) -> DateTime<Utc> {
+    // March 2024 starts on a Friday.
+    Utc.with_ymd_and_hms(2024, 3, day, hour, 0, 0).unwrap()
+  }
+
+  #[test]
+  fn future_dated_lease() {
+    let saturday = lease(Some(at(2, 10)), at(2, 12), None);
+
+    assert!(saturday.occurrence_at(at(1, 11), &Utc).is_none());
+    assert!(saturday.occurrence_at(at(2, 11), &Utc).is_some());
+    assert!(saturday.occurrence_at(at(2, 12), &Utc).is_none());
+    assert!(!saturday.is_expired(at(2, 11)));
+    assert!(saturday.is_expired(at(2, 13)));
+  }
+
+  #[test]
+  fn remaining() {
+    let saturday = lease(Some(at(2, 10)), at(2, 12), None);
+
+    assert_eq!(saturday.remaining(at(1, 11)), Duration::hours(2));
+    assert_eq!(saturday.remaining(at(2, 11)), Duration::hours(1));
+    assert_eq!(saturday.remaining(at(2, 13)), Duration::zero());
+  }
+
+  #[test]
+  fn weekly_lease() {
+    let saturdays = lease(Some(at(2, 10)), at(2, 12), Some(Recurrence::Weekly));
+
+    assert!(saturdays.occurrence_at(at(1, 11), &Utc).is_none());
+    assert!(saturdays.occurrence_at(at(2, 11), &Utc).is_some());
+    assert!(saturdays.occurrence_at(at(3, 11), &Utc).is_none());
+    assert!(saturdays.occurrence_at(at(9, 10), &Utc).is_some());
+    assert!(saturdays.occurrence_at(at(9, 12), &Utc).is_none());
+    assert!(saturdays.occurrence_at(at(30, 11), &Utc).is_some());
+    assert!(!saturdays.is_expired(at(30, 13)));
+  }
+
+  #[test]
+  fn daily_lease_past_midnight() {
+    let nightly = lease(Some(at(1, 22)), at(2, 2), Some(Recurrence::Daily));
+
+    assert!(nightly.occurrence_at(at(1, 23), &Utc).is_some());
+    assert!(nightly.occurrence_at(at(5, 1), &Utc).is_some());
+    assert!(nightly.occurrence_at(at(5, 3), &Utc).is_none());
+    assert!(nightly.occurrence_at(at(5, 22), &Utc).is_some());
+  }
+}
@@ -0,0 +1,254 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::net::IpAddr;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::file::create_file;
+use crate::generate::ConfigFiles;
+use crate::list::IdentifiedList;
+use crate::model::{Client, Conf, NetAccess};
+
+/// The file the ruleset is written to, in the backend's directory.
+pub const RULESET: &str = "penguin.nft";
+
+/// Loads the devices whose internet access is managed locally, rather than by a UniFi gateway.
+pub fn load_devices(conf: &Conf) -> Result<Vec<NetAccess>> {
+  let path = conf.local_netaccess_json();
+  if !path.exists() {
+    return Ok(Vec::new());
+  }
+  Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+}
+
+pub fn save_devices(conf: &Conf, devices: &[NetAccess]) -> Result<()> {
+  let file = create_file(conf.local_netaccess_json())?;
+  serde_json::to_writer_pretty(BufWriter::new(file), devices)?;
+  Ok(())
+}
+
+/// Whether a MAC address is six hex octets separated by colons, the only form nft accepts.
+pub fn is_valid_mac_address(mac: &str) -> bool {
+  let octets: Vec<_> = mac.split(':').collect();
+  octets.len() == 6
+    && octets
+      .iter()
+      .all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn set(name: &str, set_type: &str, elements: &[String]) -> String {
+  let mut set = format!("  set {} {{\n    type {}\n", name, set_type);
+  if !elements.is_empty() {
+    set.push_str(&format!("    elements = {{ {} }}\n", elements.join(", ")));
+  }
+  set.push_str("  }\n");
+  set
+}
+
+/// Generates an nftables ruleset that drops traffic forwarded from devices whose internet access is
+/// turned off. Devices are matched by MAC address, and by the address of any client with the same
+/// MAC address, since MAC addresses aren't seen beyond the local network.
+pub fn generate_nftables_config(
+  clients: &IdentifiedList<Client>,
+  devices: &[NetAccess],
+) -> Result<ConfigFiles> {
+  // One bad address would make nft reject the whole ruleset, unblocking every device.
+  let mut macs = Vec::new();
+  for device in devices.iter().filter(|d| !d.enabled) {
+    if is_valid_mac_address(&device.mac_address) {
+      macs.push(device.mac_address.to_lowercase());
+    } else {
+      tracing::warn!(
+        "Can't block device with MAC address {:?}",
+        device.mac_address
+      );
+    }
+  }
+
+  let mut ipv4 = Vec::new();
+  let mut ipv6 = Vec::new();
+  for client in clients.items.iter().filter(|c| {
+    c.mac_address
+      .as_ref()
+      .is_some_and(|mac| macs.contains(&mac.to_lowercase()))
+  }) {
+    match client.ip.parse::<IpAddr>() {
+      Ok(IpAddr::V4(ip)) => ipv4.push(ip.to_string()),
+      Ok(IpAddr::V6(ip)) => ipv6.push(ip.to_string()),
+      Err(_) => tracing::warn!("Can't block client with address {}", client.ip),
+    }
+  }
+
+  // Declaring the table before deleting it means the delete works even the first time, and nft
+  // applies the whole file as one transaction, so there's no moment with no table.
+  let mut ruleset = String::from(
+    "table inet penguin\n\
+     delete table inet penguin\n\
+     table inet penguin {\n",
+  );
+  ruleset.push_str(&set("blocked_macs", "ether_addr", &macs));
+  ruleset.push_str(&set("blocked_ipv4", "ipv4_addr", &ipv4));
+  ruleset.push_str(&set("blocked_ipv6", "ipv6_addr", &ipv6));
+  ruleset.push_str(
+    "  chain forward {\n\
+     \x20   type filter hook forward priority filter; policy accept;\n\
+     \x20   ether saddr @blocked_macs drop\n\
+     \x20   ip saddr @blocked_ipv4 drop\n\
+     \x20   ip6 saddr @blocked_ipv6 drop\n\
+     \x20 }\n\
+     }\n",
+  );
+
+  Ok(ConfigFiles::from([(RULESET.to_owned(), ruleset)]))
+}
+
+fn run_nft(nft_binary: &str, args: &[&str], ruleset: &Path) -> Result<()> {
+  let output = Command::new(nft_binary)
+    .args(args)
+    .arg("-f")
+    .arg(ruleset)
+    .output()
+    .with_context(|| format!("Failed to run {}", nft_binary))?;
+  if output.status.success() {
+    return Ok(());
+  }
+
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  Err(anyhow!("nft rejected the ruleset: {}", stderr.trim()))
+}
+
+/// Checks a generated ruleset with `nft -c`, without applying it.
+pub fn check_config(nft_binary: &str, dir: &Path) -> Result<()> {
+  run_nft(nft_binary, &["-c"], &dir.join(RULESET))
+}
+
+/// Replaces penguin's table with the ruleset in a configuration directory.
+pub fn load_ruleset(nft_binary: &str, dir: &Path) -> Result<()> {
+  run_nft(nft_binary, &[], &dir.join(RULESET))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tempdir::TempDir;
+
+  fn device(mac: &str, enabled: bool) -> NetAccess {
+    NetAccess {
+      mac_address: mac.to_owned(),
+      enabled,
+      auto_disable_at: None,
+    }
+  }
+
+  fn client(id: u32, ip: &str, mac: &str) -> Client {
+    Client {
+      id: Some(id),
+      ip: ip.to_owned(),
+      name: format!("client{}", id),
+      rules: vec![],
+      leases: vec![],
+      mac_address: Some(mac.to_owned()),
+      bandwidth: None,
+      safe_search: false,
+    }
+  }
+
+  #[test]
+  fn ruleset() -> Result<()> {
+    let clients = IdentifiedList::new(vec![
+      client(1, "192.168.1.33", "AA:BB:CC:DD:EE:01"),
+      client(2, "192.168.1.34", "aa:bb:cc:dd:ee:02"),
+      client(3, "fd00::3", "aa:bb:cc:dd:ee:03"),
+    ]);
+    let devices = vec![
+      device("aa:bb:cc:dd:ee:01", false),
+      device("aa:bb:cc:dd:ee:02", true),
+      device("AA:BB:CC:DD:EE:03", false),
+      device("aa } ; flush ruleset", false),
+      device("AA-BB-CC-DD-EE-04", false),
+    ];
+
+    let files = generate_nftables_config(&clients, &devices)?;
+    assert_eq!(
+      files[RULESET],
+      "table inet penguin\n\
+       delete table inet penguin\n\
+       table inet penguin {\n\
+       \x20 set blocked_macs {\n\
+       \x20   type ether_addr\n\
+       \x20   elements = { aa:bb:cc:dd:ee:01, aa:bb:cc:dd:ee:03 }\n\
+       \x20 }\n\
+       \x20 set blocked_ipv4 {\n\
+       \x20   type ipv4_addr\n\
+       \x20   elements = { 192.168.1.33 }\n\
+       \x20 }\n\
+       \x20 set blocked_ipv6 {\n\
+       \x20   type ipv6_addr\n\
+       \x20   elements = { fd00::3 }\n\
+       \x20 }\n\
+       \x20 chain forward {\n\
+       \x20   type filter hook forward priority filter; policy accept;\n\
+       \x20   ether saddr @blocked_macs drop\n\
+       \x20   ip saddr @blocked_ipv4 drop\n\
+       \x20   ip6 saddr @blocked_ipv6 drop\n\
+       \x20 }\n\
+       }\n"
+    );
+
+    let files = generate_nftables_config(&clients, &[])?;
+    assert!(files[RULESET].contains("  set blocked_macs {\n    type ether_addr\n  }\n"));
+
+    Ok(())
+  }
+
+  #[test]
+  fn mac_addresses() {
+    assert!(is_valid_mac_address("aa:bb:cc:dd:ee:0F"));
+    assert!(!is_valid_mac_address("aa-bb-cc-dd-ee-0f"));
+    assert!(!is_valid_mac_address("aa:bb:cc:dd:ee"));
+    assert!(!is_valid_mac_address("aa:bb:cc:dd:ee:0g"));
+    assert!(!is_valid_mac_address("aa:bb:cc:dd:ee:f"));
+    assert!(!is_valid_mac_address("aa } ; flush ruleset"));
+  }
+
+  #[test]
+  fn runs_nft() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // A stand-in for nft that records how it was run, and rejects rulesets containing "bogus".
+    let dir = TempDir::new("penguin-nftables-test")?;
+    let nft = dir.path().join("nft");
+    let log = dir.path().join("log");
+    std::fs::write(
+      &nft,
+      format!(
+        "#!/bin/sh\n\
+         echo \"$@\" >> {}\n\
+         eval last=\\${{$#}}\n\
+         if grep -q bogus \"$last\"; then echo \"Error: syntax error\" >&2; exit 1; fi\n",
+        log.display()
+      ),
+    )?;
+    std::fs::set_permissions(&nft, std::fs::Permissions::from_mode(0o755))?;
+    let nft = nft.to_str().unwrap();
+
+    std::fs::write(dir.path().join(RULESET), "table inet penguin {}\n")?;
+    check_config(nft, dir.path())?;
+    load_ruleset(nft, dir.path())?;
+    let ruleset = dir.path().join(RULESET).display().to_string();
+    assert_eq!(
+      std::fs::read_to_string(&log)?,
+      format!("-c -f {}\n-f {}\n", ruleset, ruleset)
+    );
+
+    std::fs::write(dir.path().join(RULESET), "bogus\n")?;
+    assert_eq!(
+      check_config(nft, dir.path()).unwrap_err().to_string(),
+      "nft rejected the ruleset: Error: syntax error"
+    );
+
+    Ok(())
+  }
+}
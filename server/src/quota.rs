@@ -0,0 +1,260 @@
+use std::collections::{BTreeSet, HashSet};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::serde::ts_milliseconds;
+use chrono::{DateTime, Local, NaiveTime, TimeZone, Utc};
+use serde::Serialize;
+
+use crate::domain::{domain_matches, flatten, url_host, ListMatcher};
+use crate::list::IdentifiedList;
+use crate::model::{Client, Conf, DomainList, RuleKind};
+use crate::squid::{get_logs_since, LogEntry};
+
+/// The quota rules that have used up their budget in the current period, as pairs of client id and
+/// rule index.
+pub type ExhaustedQuotas = BTreeSet<(u32, usize)>;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct QuotaStatus {
+  pub rule_index: usize,
+  pub domainlists: Vec<u32>,
+  pub budget_minutes: u32,
+  pub used_minutes: u32,
+  pub remaining_minutes: u32,
+  #[serde(with = "ts_milliseconds")]
+  pub resets_at_utc: DateTime<Utc>,
+}
+
+/// Returns the start and end of the quota period containing `now`. Periods run from one reset time
+/// to the next.
+fn period<T: TimeZone>(
+  now: DateTime<T>,
+  reset_time: NaiveTime,
+) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+  let tz = now.timezone();
+  let mut day = now.date_naive();
+  if now.time() < reset_time {
+    day = day.pred_opt().ok_or_else(|| anyhow!("Date out of range"))?;
+  }
+  let next_day = day.succ_opt().ok_or_else(|| anyhow!("Date out of range"))?;
+
+  let at_reset = |day: chrono::NaiveDate| {
+    tz.from_local_datetime(&day.and_time(reset_time))
+      .earliest()
+      .map(|d| d.with_timezone(&Utc))
+      .ok_or_else(|| anyhow!("Reset time {} doesn't exist on {}", reset_time, day))
+  };
+
+  Ok((at_reset(day)?, at_reset(next_day)?))
+}
+
+fn current_period(conf: &Conf) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+  let reset_time = NaiveTime::parse_from_str(&conf.quota_reset_time, "%H:%M")
+    .with_context(|| format!("Invalid quota_reset_time '{}'", conf.quota_reset_time))?;
+  period(Local::now(), reset_time)
+}
+
+fn has_quota(client: &Client) -> bool {
+  client
+    .rules
+    .iter()
+    .any(|r| r.kind == RuleKind::QuotaHttpAccess)
+}
+
+/// Works out how much of each of the client's quotas has been used in the given period. Usage is
+/// counted in active minutes: any minute in which the client made at least one request to a domain
+/// covered by the quota.
+fn client_quotas(
+  client: &Client,
+  domainlists: &IdentifiedList<DomainList>,
+  logs: &[LogEntry],
+  period: (DateTime<Utc>, DateTime<Utc>),
+) -> Vec<QuotaStatus> {
+  let (start, end) = period;
+  let client_logs: Vec<_> = logs
+    .iter()
+    .filter(|e| e.client_ip == client.ip && e.date >= start && e.date < end)
+    .collect();
+
+  let mut result = Vec::new();
+  for (index, rule) in client.rules.iter().enumerate() {
+    if rule.kind != RuleKind::QuotaHttpAccess {
+      continue;
+    }
+
+    let matchers = |ids: &[u32]| -> Vec<_> {
+      domainlists
+        .items
+        .iter()
+        .filter(|l| ids.iter().any(|id| l.id == Some(*id)))
+        // Lists that include each other are rejected when saved, so just ignore the includes.
+        .map(|l| ListMatcher::new(&flatten(domainlists, l).unwrap_or_else(|_| l.clone())))
+        .collect()
+    };
+    let counted = matchers(&rule.domainlists);
+    let exceptions = matchers(&rule.exception_domainlists);
+
+    // Time spent on the rule's exceptions doesn't count towards the quota.
+    let is_exception = |url: &str| {
+      let host = url_host(url);
+      rule
+        .exception_domains
+        .iter()
+        .any(|d| domain_matches(host, d))
+        || exceptions.iter().any(|m| m.matches(url))
+    };
+
+    let minutes: HashSet<_> = client_logs
+      .iter()
+      .filter(|e| counted.iter().any(|m| m.matches(&e.request_url)))
+      .filter(|e| !is_exception(&e.request_url))
+      .map(|e| e.date.timestamp() / 60)
+      .collect();
+
+    let budget_minutes = rule.daily_quota_minutes.unwrap_or(0);
+    let used_minutes = minutes.len() as u32;
+    result.push(QuotaStatus {
+      rule_index: index,
+      domainlists: rule.domainlists.clone(),
+      budget_minutes,
+      used_minutes,
+      remaining_minutes: budget_minutes.saturating_sub(used_minutes),
+      resets_at_utc: end,
+    });
+  }
+
+  result
+}
+
+/// Gets the status of each of a client's quotas from the proxy logs.
+pub fn get_client_quotas(
+  conf: &Conf,
+  client: &Client,
+  domainlists: &IdentifiedList<DomainList>,
+) -> Result<Vec<QuotaStatus>> {
+  if !has_quota(client) {
+    return Ok(Vec::new());
+  }
+
+  let period = current_period(conf)?;
+  let logs = get_logs_since(&conf.squid_log_dir, period.0)?;
+  Ok(client_quotas(client, domainlists, &logs, period))
+}
+
+/// Finds all quota rules that have used up their budget, so they can be enforced.
+pub fn exhausted_quotas(
+  conf: &Conf,
+  clients: &IdentifiedList<Client>,
+  domainlists: &IdentifiedList<DomainList>,
+) -> Result<ExhaustedQuotas> {
+  let mut exhausted = ExhaustedQuotas::new();
+  if !clients.items.iter().any(has_quota) {
+    return Ok(exhausted);
+  }
+
+  let period = current_period(conf)?;
+  let logs = get_logs_since(&conf.squid_log_dir, period.0)?;
+  for client in clients.items.iter().filter(|c| has_quota(c)) {
+    for status in client_quotas(client, domainlists, &logs, period) {
+      if status.remaining_minutes == 0 {
+        exhausted.insert((client.id.unwrap(), status.rule_index));
+      }
+    }
+  }
+
+  Ok(exhausted)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::model::{ListKind, Rule};
+  use chrono::FixedOffset;
+
+  #[test]
+  fn periods() -> Result<()> {
+    let tz = FixedOffset::west_opt(7 * 3600).unwrap();
+    let reset = NaiveTime::from_hms_opt(4, 0, 0).unwrap();
+
+    let (start, end) = period(tz.with_ymd_and_hms(2024, 3, 10, 2, 0, 0).unwrap(), reset)?;
+    assert_eq!(start, tz.with_ymd_and_hms(2024, 3, 9, 4, 0, 0).unwrap());
+    assert_eq!(end, tz.with_ymd_and_hms(2024, 3, 10, 4, 0, 0).unwrap());
+
+    let (start, _) = period(tz.with_ymd_and_hms(2024, 3, 10, 5, 0, 0).unwrap(), reset)?;
+    assert_eq!(start, tz.with_ymd_and_hms(2024, 3, 10, 4, 0, 0).unwrap());
+
+    Ok(())
+  }
+
+  #[test]
+  fn counts_active_minutes() -> Result<()> {
+    let client = Client {
+      id: Some(1),
+      ip: "192.168.1.224".to_owned(),
+      name: "Laptop".to_owned(),
+      rules: vec![Rule {
+        kind: RuleKind::QuotaHttpAccess,
+        domainlists: vec![1],
+        domains: vec![],
+        exception_domains: vec!["kids.youtube.com".to_owned()],
+        exception_domainlists: vec![],
+        mime_types: vec![],
+        file_extensions: vec![],
+        schedule: None,
+        daily_quota_minutes: Some(2),
+      }],
+      leases: vec![],
+      mac_address: None,
+      bandwidth: None,
+      safe_search: false,
+    };
+    let domainlists = IdentifiedList::new(vec![DomainList {
+      id: Some(1),
+      name: "video".to_owned(),
+      kind: ListKind::Dstdomain,
+      domains: vec![".youtube.com".to_owned()],
+      includes: vec![],
+      source: None,
+    }]);
+
+    let line = |time: &str, ip: &str, url: &str| {
+      LogEntry::parse(&format!(
+        "2023-10-04T{}.000-0700 1374 {} {} TCP_TUNNEL/200 10630 CONNECT {} - HIER_DIRECT/{} -",
+        time, ip, ip, url, url
+      ))
+    };
+    let logs = vec![
+      line("10:00:05", "192.168.1.224", "www.youtube.com:443")?,
+      line("10:00:45", "192.168.1.224", "i.youtube.com:443")?,
+      line("10:01:10", "192.168.1.224", "www.google.com:443")?,
+      line("10:02:10", "192.168.1.100", "www.youtube.com:443")?,
+      line("10:05:00", "192.168.1.224", "www.youtube.com:443")?,
+      line("10:06:00", "192.168.1.224", "kids.youtube.com:443")?,
+    ];
+
+    let tz = FixedOffset::west_opt(7 * 3600).unwrap();
+    let start = tz
+      .with_ymd_and_hms(2023, 10, 4, 0, 0, 0)
+      .unwrap()
+      .with_timezone(&Utc);
+    let end = tz
+      .with_ymd_and_hms(2023, 10, 5, 0, 0, 0)
+      .unwrap()
+      .with_timezone(&Utc);
+
+    let statuses = client_quotas(&client, &domainlists, &logs, (start, end));
+    assert_eq!(
+      statuses,
+      vec![QuotaStatus {
+        rule_index: 0,
+        domainlists: vec![1],
+        budget_minutes: 2,
+        used_minutes: 2,
+        remaining_minutes: 0,
+        resets_at_utc: end,
+      }]
+    );
+
+    Ok(())
+  }
+}
@@ -0,0 +1,241 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{
+  DateTime, Datelike, FixedOffset, Local, NaiveDateTime, NaiveTime, Offset, TimeZone, Timelike, Utc,
+};
+use chrono_tz::Tz;
+
+use crate::model::Schedule;
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+const MINUTES_PER_WEEK: i64 = 7 * MINUTES_PER_DAY;
+
+/// Squid's single letter abbreviations for the days of the week, starting with Sunday.
+const SQUID_DAYS: [char; 7] = ['S', 'M', 'T', 'W', 'H', 'F', 'A'];
+
+fn parse_time(s: &str) -> Result<i64> {
+  let time = NaiveTime::parse_from_str(s, "%H:%M")
+    .with_context(|| format!("Invalid time '{}'. Expected HH:MM", s))?;
+  Ok((time.hour() * 60 + time.minute()) as i64)
+}
+
+fn format_time(minutes: i64) -> String {
+  format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+fn parse_timezone(name: &str) -> Result<Tz> {
+  name
+    .parse()
+    .map_err(|_| anyhow!("Unknown timezone '{}'", name))
+}
+
+/// Returns the periods the schedule is active for, as the minute of the week (from midnight at the
+/// start of Sunday) each one starts at in the schedule's timezone, and its length in minutes.
+fn active_periods(schedule: &Schedule) -> Result<Vec<(i64, i64)>> {
+  let days: Vec<i64> = if schedule.days.is_empty() {
+    (0..7).collect()
+  } else {
+    schedule.days.iter().map(|d| *d as i64).collect()
+  };
+
+  let mut ranges = Vec::new();
+  for range in schedule.times.iter() {
+    let start = parse_time(&range.start)?;
+    let mut end = parse_time(&range.end)?;
+    if end <= start {
+      end += MINUTES_PER_DAY;
+    }
+    ranges.push((start, end));
+  }
+  if ranges.is_empty() {
+    ranges.push((0, MINUTES_PER_DAY));
+  }
+
+  Ok(
+    days
+      .iter()
+      .flat_map(|day| {
+        ranges
+          .iter()
+          .map(move |(start, end)| (day * MINUTES_PER_DAY + start, end - start))
+      })
+      .collect(),
+  )
+}
+
+/// Returns whether the schedule is active at the given time.
+pub fn is_active(schedule: &Schedule, now: DateTime<Utc>) -> Result<bool> {
+  let time: NaiveDateTime = match &schedule.timezone {
+    None => now.with_timezone(&Local).naive_local(),
+    Some(name) => now.with_timezone(&parse_timezone(name)?).naive_local(),
+  };
+  let minute = time.weekday().num_days_from_sunday() as i64 * MINUTES_PER_DAY
+    + (time.hour() * 60 + time.minute()) as i64;
+
+  Ok(
+    active_periods(schedule)?
+      .iter()
+      .any(|(start, len)| (minute - start).rem_euclid(MINUTES_PER_WEEK) < *len),
+  )
+}
+
+/// Returns the number of minutes to add to a time in the schedule's timezone to get the same
+/// instant in the local timezone.
+fn shift_minutes(schedule: &Schedule, now: DateTime<Utc>, local: FixedOffset) -> Result<i64> {
+  match &schedule.timezone {
+    None => Ok(0),
+    Some(name) => {
+      let tz = parse_timezone(name)?;
+      let offset = tz.offset_from_utc_datetime(&now.naive_utc()).fix();
+      Ok(((local.local_minus_utc() - offset.local_minus_utc()) / 60) as i64)
+    }
+  }
+}
+
+/// Returns the values of one or more squid `time` acl lines that together match while the schedule
+/// is active. Squid evaluates time acls in the server's local timezone, so schedules in another
+/// timezone are converted using the offsets in effect when the config is generated.
+pub fn squid_time_specs(schedule: &Schedule) -> Result<Vec<String>> {
+  let now = Utc::now();
+  squid_time_specs_at(schedule, now, *now.with_timezone(&Local).offset())
+}
+
+fn squid_time_specs_at(
+  schedule: &Schedule,
+  now: DateTime<Utc>,
+  local: FixedOffset,
+) -> Result<Vec<String>> {
+  let shift = shift_minutes(schedule, now, local)?;
+
+  // Squid time acls can't cross midnight, so split each active period into pieces that fall within
+  // a single local day, then group together the days that share the same times.
+  let mut days_by_time: BTreeMap<(i64, i64), Vec<i64>> = BTreeMap::new();
+  for (start, len) in active_periods(schedule)? {
+    let mut from = (start + shift).rem_euclid(MINUTES_PER_WEEK);
+    let mut remaining = len;
+    while remaining > 0 {
+      let time_of_day = from % MINUTES_PER_DAY;
+      let len = remaining.min(MINUTES_PER_DAY - time_of_day);
+      days_by_time
+        .entry((time_of_day, time_of_day + len))
+        .or_default()
+        .push(from / MINUTES_PER_DAY);
+      remaining -= len;
+      from = (from + len) % MINUTES_PER_WEEK;
+    }
+  }
+
+  Ok(
+    days_by_time
+      .into_iter()
+      .map(|((start, end), mut days)| {
+        days.sort();
+        days.dedup();
+        let days: String = days.iter().map(|d| SQUID_DAYS[*d as usize]).collect();
+        // Squid's time ranges include the end minute.
+        format!("{} {}-{}", days, format_time(start), format_time(end - 1))
+      })
+      .collect(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::model::{Day, TimeRange};
+
+  fn schedule(days: Vec<Day>, times: Vec<(&str, &str)>, timezone: Option<&str>) -> Schedule {
+    Schedule {
+      days,
+      times: times
+        .into_iter()
+        .map(|(start, end)| TimeRange {
+          start: start.to_owned(),
+          end: end.to_owned(),
+        })
+        .collect(),
+      timezone: timezone.map(|t| t.to_owned()),
+    }
+  }
+
+  fn specs(schedule: &Schedule) -> Result<Vec<String>> {
+    let now = Utc.with_ymd_and_hms(2024, 7, 1, 12, 0, 0).unwrap();
+    squid_time_specs_at(schedule, now, FixedOffset::west_opt(7 * 3600).unwrap())
+  }
+
+  #[test]
+  fn all_week() -> Result<()> {
+    assert_eq!(
+      specs(&schedule(vec![], vec![], None))?,
+      vec!["SMTWHFA 00:00-23:59"]
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn school_nights() -> Result<()> {
+    let school_nights = schedule(
+      vec![Day::Sun, Day::Mon, Day::Tue, Day::Wed, Day::Thu],
+      vec![("19:00", "07:00")],
+      None,
+    );
+
+    assert_eq!(
+      specs(&school_nights)?,
+      vec!["MTWHF 00:00-06:59", "SMTWH 19:00-23:59"]
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn converts_timezone() -> Result<()> {
+    // New York is three hours ahead of the (PDT) server in July.
+    let work_hours = schedule(
+      vec![Day::Mon],
+      vec![("09:00", "17:00")],
+      Some("America/New_York"),
+    );
+    assert_eq!(specs(&work_hours)?, vec!["M 06:00-13:59"]);
+
+    // Early Sunday morning in UTC is still Saturday on the server.
+    let early = schedule(vec![Day::Sun], vec![("01:00", "02:00")], Some("UTC"));
+    assert_eq!(specs(&early)?, vec!["A 18:00-18:59"]);
+    Ok(())
+  }
+
+  #[test]
+  fn active() -> Result<()> {
+    let school_nights = schedule(
+      vec![Day::Sun, Day::Mon, Day::Tue, Day::Wed, Day::Thu],
+      vec![("19:00", "07:00")],
+      Some("UTC"),
+    );
+    // 2024-07-01 was a Monday.
+    let at = |day, hour, minute| Utc.with_ymd_and_hms(2024, 7, day, hour, minute, 0).unwrap();
+    assert!(is_active(&school_nights, at(1, 19, 0))?);
+    assert!(is_active(&school_nights, at(2, 6, 59))?);
+    assert!(!is_active(&school_nights, at(2, 7, 0))?);
+    assert!(!is_active(&school_nights, at(1, 18, 59))?);
+    // Sunday night runs into Monday morning, but Friday night doesn't start.
+    assert!(is_active(&school_nights, at(1, 3, 0))?);
+    assert!(!is_active(&school_nights, at(5, 20, 0))?);
+    assert!(is_active(&school_nights, at(5, 6, 0))?);
+    assert!(!is_active(&school_nights, at(6, 6, 0))?);
+
+    let work_hours = schedule(
+      vec![Day::Mon],
+      vec![("09:00", "17:00")],
+      Some("America/New_York"),
+    );
+    assert!(is_active(&work_hours, at(1, 13, 0))?);
+    assert!(!is_active(&work_hours, at(1, 21, 30))?);
+    Ok(())
+  }
+
+  #[test]
+  fn rejects_invalid() {
+    assert!(specs(&schedule(vec![], vec![("7pm", "07:00")], None)).is_err());
+    assert!(specs(&schedule(vec![], vec![], Some("Mars/Olympus_Mons"))).is_err());
+  }
+}
@@ -1,7 +1,7 @@
 use std::{
   fs::File,
   io::{BufReader, Read},
-  path::PathBuf,
+  path::{Path, PathBuf},
   process::Command,
 };
 
@@ -11,6 +11,7 @@ use flate2::read::GzDecoder;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::io::BufRead;
+use tempdir::TempDir;
 use tracing::{error, warn};
 
 /// Request that squid reload its configuration.
@@ -27,6 +28,45 @@ pub fn reload_config() {
   }
 }
 
+/// The files of a generated configuration directory, in the order that penguin.conf includes them.
+const INCLUDES: [&str; 3] = ["domains.conf", "delay_pools.conf", "client_*.conf"];
+
+/// Checks a generated configuration directory with `squid -k parse`, using a squid.conf that
+/// includes just the generated files. Returns squid's complaints if it doesn't parse.
+pub fn check_config(squid_binary: &str, dir: &Path) -> Result<()> {
+  let check_dir = TempDir::new("penguin-squid-check")?;
+  let squid_conf = check_dir.path().join("squid.conf");
+  let includes: Vec<_> = INCLUDES
+    .iter()
+    .map(|file| format!("include {}\n", dir.join(file).display()))
+    .collect();
+  std::fs::write(&squid_conf, includes.concat())?;
+
+  let output = Command::new(squid_binary)
+    .arg("-k")
+    .arg("parse")
+    .arg("-f")
+    .arg(&squid_conf)
+    .output()
+    .with_context(|| format!("Failed to run {}", squid_binary))?;
+  if output.status.success() {
+    return Ok(());
+  }
+
+  // Squid logs a lot while parsing, so only keep the lines that say what's wrong.
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  let problems: Vec<_> = stderr
+    .lines()
+    .filter(|l| l.contains("FATAL") || l.contains("ERROR") || l.contains("Bungled"))
+    .collect();
+  let message = if problems.is_empty() {
+    stderr.trim()
+  } else {
+    &problems.join("\n")
+  };
+  Err(anyhow!("Squid rejected the configuration: {}", message))
+}
+
 #[derive(PartialEq, Debug, Serialize, Deserialize)]
 pub enum ActiveState {
   Active,
@@ -112,9 +152,15 @@ pub fn set_running(running: bool) -> Result<ServiceStatus> {
 }
 
 /// Gets all Squid logs by reading files in the log directory.
-/// TODO: hoist log location out into a config variable.
-pub fn get_all_logs() -> Result<Vec<LogEntry>> {
-  let log_dir = PathBuf::from("/var/log/squid");
+pub fn get_all_logs<P: Into<PathBuf>>(log_dir: P) -> Result<Vec<LogEntry>> {
+  get_logs_since(log_dir, DateTime::<Utc>::MIN_UTC)
+}
+
+/// Gets the Squid logs of requests made since the given time. Files that were last written to
+/// before then (rotating and compressing them keeps their modification time) can't have any, so
+/// they aren't read at all.
+pub fn get_logs_since<P: Into<PathBuf>>(log_dir: P, since: DateTime<Utc>) -> Result<Vec<LogEntry>> {
+  let log_dir: PathBuf = log_dir.into();
 
   let mut result = Vec::new();
   if log_dir.exists() {
@@ -123,24 +169,43 @@ pub fn get_all_logs() -> Result<Vec<LogEntry>> {
       if let Some(file_name) = path.file_name() {
         let file_name = file_name.to_str().ok_or_else(|| anyhow!("Invalid path"))?;
         if file_name.starts_with("access.log") {
+          let modified: DateTime<Utc> = path.metadata()?.modified()?.into();
+          if modified < since {
+            continue;
+          }
           let file = File::open(&path)?;
           if file_name.ends_with(".gz") {
-            read_logs(GzDecoder::new(file), &mut result)?;
+            read_logs(&path, GzDecoder::new(file), &mut result)?;
           } else {
-            read_logs(file, &mut result)?;
+            read_logs(&path, file, &mut result)?;
           };
         }
       }
     }
   }
+  result.retain(|e| e.date >= since);
 
   Ok(result)
 }
 
-fn read_logs<R: Read>(read: R, out: &mut Vec<LogEntry>) -> Result<()> {
+/// Reads the entries in a log file. Lines that can't be parsed, such as one squid was part way
+/// through writing, are skipped rather than making the whole file unreadable.
+fn read_logs<R: Read>(path: &Path, read: R, out: &mut Vec<LogEntry>) -> Result<()> {
   let buf = BufReader::new(read);
+  let mut skipped = 0;
   for line in buf.lines() {
-    out.push(LogEntry::parse(&line?)?);
+    match LogEntry::parse(&line?) {
+      Ok(entry) => out.push(entry),
+      Err(e) => {
+        if skipped == 0 {
+          warn!("Skipping unparsable line in {}: {:#}", path.display(), e);
+        }
+        skipped += 1;
+      }
+    }
+  }
+  if skipped > 1 {
+    warn!("Skipped {} unparsable lines in {}", skipped, path.display());
   }
 
   Ok(())
@@ -149,14 +214,14 @@ fn read_logs<R: Read>(read: R, out: &mut Vec<LogEntry>) -> Result<()> {
 #[derive(PartialEq, Serialize, Deserialize, Debug)]
 pub struct LogEntry {
   #[serde(with = "ts_milliseconds")]
-  date: DateTime<Utc>,
+  pub date: DateTime<Utc>,
   response_time_millis: u64,
   pub client_ip: String,
   client_fqdn: String,
   status_code: String,
   request_size_bytes: u64,
   request_method: String,
-  request_url: String,
+  pub request_url: String,
   username: String,
   peer_fqdn: String,
   mime_type: String,
@@ -164,7 +229,7 @@ pub struct LogEntry {
 
 impl LogEntry {
   #[rustfmt::skip]
-  fn parse(s: &str) -> Result<Self> {
+  pub fn parse(s: &str) -> Result<Self> {
     let mut parts = s.split_whitespace();
 
     let first = parts.next().ok_or(anyhow!("Missing date"))?.to_owned();
@@ -201,6 +266,82 @@ mod tests {
   use super::*;
   use chrono::TimeZone;
 
+  /// Writes a stand-in for squid that rejects any included file containing "bogus".
+  fn fake_squid(dir: &Path) -> Result<String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = dir.join("squid");
+    std::fs::write(
+      &path,
+      "#!/bin/sh\n\
+       for f in $(sed -n 's/^include //p' \"$4\"); do\n\
+         if grep -q bogus \"$f\"; then\n\
+           echo \"2024/03/04 23:28:53| Processing: bogus\" >&2\n\
+           echo \"FATAL: Bungled $(basename $f) line 1: bogus\" >&2\n\
+           exit 1\n\
+         fi\n\
+       done\n",
+    )?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+    Ok(path.to_str().unwrap().to_owned())
+  }
+
+  #[test]
+  fn checks_config() -> Result<()> {
+    let dir = TempDir::new("penguin-squid-test")?;
+    let squid = fake_squid(dir.path())?;
+    let generated = dir.path().join("generated");
+    std::fs::create_dir(&generated)?;
+    std::fs::write(
+      generated.join("domains.conf"),
+      "acl domains_0001 dstdomain .example.com\n",
+    )?;
+    std::fs::write(
+      generated.join("client_0001.conf"),
+      "acl client_0001 src 192.168.1.33\n",
+    )?;
+    check_config(&squid, &generated)?;
+
+    std::fs::write(generated.join("client_0002.conf"), "bogus\n")?;
+    let error = check_config(&squid, &generated).unwrap_err();
+    assert_eq!(
+      error.to_string(),
+      "Squid rejected the configuration: FATAL: Bungled client_0002.conf line 1: bogus"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn reads_recent_logs() -> Result<()> {
+    use std::time::{Duration, SystemTime};
+
+    let dir = TempDir::new("penguin-squid-test")?;
+    std::fs::write(
+      dir.path().join("access.log"),
+      "2023-10-04T00:14:09.000-0700   1374 192.168.1.224 192.168.1.224 TCP_TUNNEL/200 10630 CONNECT old.example.com:443 - HIER_DIRECT/old.example.com -\n\
+       not a log line\n\
+       2023-10-05T00:14:09.000-0700   1374 192.168.1.224 192.168.1.224 TCP_TUNNEL/200 10630 CONNECT new.example.com:443 - HIER_DIRECT/new.example.com -\n\
+       2023-10-05T00:15:0",
+    )?;
+    // A rotated file from before the period isn't read, so it doesn't matter that it isn't gzipped.
+    let rotated = dir.path().join("access.log.2.gz");
+    std::fs::write(&rotated, "not gzip")?;
+    File::options().write(true).open(&rotated)?.set_modified(SystemTime::now() - Duration::from_secs(86400))?;
+
+    let since = Utc::now() - chrono::Duration::hours(1);
+    assert!(get_logs_since(dir.path(), since)?.is_empty());
+    assert!(get_all_logs(dir.path()).is_err());
+
+    std::fs::remove_file(&rotated)?;
+    let since = Utc.with_ymd_and_hms(2023, 10, 5, 0, 0, 0).unwrap();
+    let urls: Vec<_> = get_logs_since(dir.path(), since)?.into_iter().map(|e| e.request_url).collect();
+    assert_eq!(urls, vec!["new.example.com:443"]);
+    assert_eq!(get_all_logs(dir.path())?.len(), 2);
+
+    Ok(())
+  }
+
   #[test]
   fn check_parse() -> Result<()> {
     let log_line = "2023-10-04T00:14:09.000-0700   1374 192.168.1.224 192.168.1.224 TCP_TUNNEL/200 10630 CONNECT weather-data.apple.com:443 - HIER_DIRECT/weather-data.apple.com -";
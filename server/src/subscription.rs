@@ -0,0 +1,289 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use reqwest::{header, StatusCode};
+
+use crate::domain::{normalize_domain, remove_overlaps};
+use crate::listformat::parse;
+use crate::model::{Conf, DomainList, ListSource};
+use crate::restlist::JsonRestList;
+
+/// The shortest time between checks of a source, so a list can't be fetched on every pass of the
+/// subscription loop.
+pub const MIN_REFRESH_MINUTES: u32 = 15;
+
+enum Fetched {
+  Unchanged,
+  Changed { text: String, etag: Option<String> },
+}
+
+pub fn is_url(location: &str) -> bool {
+  location.starts_with("http://") || location.starts_with("https://")
+}
+
+/// Resolves a source that isn't a URL to a file in the configuration directory. Relative locations
+/// are taken to be in that directory, and files anywhere else are refused, so that whoever can edit
+/// a list can't have penguin read any file it can.
+pub fn local_path(config_dir: &Path, location: &str) -> Result<PathBuf> {
+  let dir = config_dir
+    .canonicalize()
+    .with_context(|| format!("Can't find {}", config_dir.display()))?;
+  let path = dir
+    .join(location)
+    .canonicalize()
+    .with_context(|| format!("Can't find {}", location))?;
+  if !path.starts_with(&dir) {
+    return Err(anyhow!(
+      "{} isn't a URL or a file in {}",
+      location,
+      dir.display()
+    ));
+  }
+  Ok(path)
+}
+
+async fn fetch(config_dir: &Path, source: &ListSource) -> Result<Fetched> {
+  if !is_url(&source.location) {
+    let (config_dir, location) = (config_dir.to_owned(), source.location.clone());
+    let text = tokio::task::spawn_blocking(move || -> Result<String> {
+      Ok(std::fs::read_to_string(local_path(
+        &config_dir,
+        &location,
+      )?)?)
+    })
+    .await??;
+    return Ok(Fetched::Changed { text, etag: None });
+  }
+
+  let client = reqwest::Client::builder()
+    .timeout(std::time::Duration::from_secs(60))
+    .build()?;
+  let mut request = client.get(&source.location);
+  if let Some(etag) = &source.etag {
+    request = request.header(header::IF_NONE_MATCH, etag);
+  }
+
+  let response = request.send().await?;
+  if response.status() == StatusCode::NOT_MODIFIED {
+    return Ok(Fetched::Unchanged);
+  }
+  let response = response.error_for_status()?;
+  let etag = response
+    .headers()
+    .get(header::ETAG)
+    .and_then(|v| v.to_str().ok())
+    .map(|v| v.to_owned());
+
+  Ok(Fetched::Changed {
+    text: response.text().await?,
+    etag,
+  })
+}
+
+/// Whether a list has a source that's due to be checked for changes.
+pub fn is_due(list: &DomainList, now: DateTime<Utc>) -> bool {
+  list.source.as_ref().is_some_and(|s| {
+    s.last_checked_utc.is_none_or(|checked| {
+      now - checked >= Duration::minutes(s.refresh_minutes.max(MIN_REFRESH_MINUTES).into())
+    })
+  })
+}
+
+/// Fetches a list's source and replaces its domains with the ones found there. Entries that aren't
+/// valid domains are skipped. Failures are recorded in the source rather than returned, and leave
+/// the domains as they were. Returns whether the domains changed.
+pub async fn refresh(config_dir: &Path, list: &mut DomainList, now: DateTime<Utc>) -> bool {
+  let Some(source) = list.source.as_mut() else {
+    return false;
+  };
+  source.last_checked_utc = Some(now);
+
+  let result = fetch(config_dir, source)
+    .await
+    .and_then(|fetched| match fetched {
+      Fetched::Unchanged => Ok(None),
+      Fetched::Changed { text, etag } => {
+        let domains: Vec<_> = parse(source.format, &text)
+          .iter()
+          .filter_map(|e| normalize_domain(e).ok())
+          .collect();
+        // An empty list is more likely to be an error page than a source that's been emptied.
+        if domains.is_empty() {
+          return Err(anyhow!("No domains found in {}", source.location));
+        }
+        Ok(Some((remove_overlaps(&domains), etag)))
+      }
+    });
+
+  match result {
+    Ok(None) => {
+      source.last_error = None;
+      false
+    }
+    Ok(Some((domains, etag))) => {
+      source.etag = etag;
+      source.last_error = None;
+      if domains == list.domains {
+        return false;
+      }
+      source.last_updated_utc = Some(now);
+      list.domains = domains;
+      true
+    }
+    Err(e) => {
+      tracing::warn!("Failed to refresh domain list {:?}: {:?}", list.id, e);
+      source.last_error = Some(format!("{:#}", e));
+      false
+    }
+  }
+}
+
+/// Saves lists that have been refreshed. The lists are reloaded first, since they may have been
+/// changed while the sources were being fetched, and lists whose source has since changed are left
+/// alone.
+pub fn store(conf: &Conf, refreshed: Vec<DomainList>) -> Result<()> {
+  let mut lists = JsonRestList::<DomainList>::load(conf.domains_json())?;
+  for list in refreshed {
+    let current = lists.list.items.iter_mut().find(|l| l.id == list.id);
+    if let Some(current) = current {
+      let location = |l: &DomainList| l.source.as_ref().map(|s| s.location.clone());
+      if location(current) == location(&list) {
+        current.domains = list.domains;
+        current.source = list.source;
+      }
+    }
+  }
+  lists.save()
+}
+
+/// Refreshes every list whose source is due to be checked. Returns whether any list changed.
+pub async fn refresh_due(conf: &Conf) -> Result<bool> {
+  let lists = JsonRestList::<DomainList>::load(conf.domains_json())?;
+  let now = Utc::now();
+
+  let mut changed = false;
+  let mut refreshed = Vec::new();
+  for list in lists.list.items.iter().filter(|l| is_due(l, now)) {
+    let mut list = list.clone();
+    changed |= refresh(&conf.config_path(), &mut list, now).await;
+    refreshed.push(list);
+  }
+
+  if !refreshed.is_empty() {
+    store(conf, refreshed)?;
+  }
+  Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::model::{ListFormat, ListKind};
+  use axum::http::{HeaderMap, StatusCode};
+  use axum::response::IntoResponse;
+  use axum::{routing, Router};
+  use std::net::TcpListener;
+  use tempdir::TempDir;
+
+  fn subscribed(location: &str, format: ListFormat) -> DomainList {
+    DomainList {
+      id: Some(1),
+      name: "gambling".to_owned(),
+      kind: ListKind::Dstdomain,
+      domains: vec![],
+      includes: vec![],
+      source: Some(ListSource {
+        location: location.to_owned(),
+        format,
+        refresh_minutes: 60,
+        etag: None,
+        last_checked_utc: None,
+        last_updated_utc: None,
+        last_error: None,
+      }),
+    }
+  }
+
+  #[tokio::test]
+  async fn local_file() -> Result<()> {
+    let dir = TempDir::new("penguin-subscription-test")?;
+    let path = dir.path().join("hosts");
+    std::fs::write(&path, "0.0.0.0 casino.example\n0.0.0.0 Poker.example\n")?;
+
+    let mut list = subscribed("hosts", ListFormat::Hosts);
+    let now = Utc::now();
+    assert!(is_due(&list, now));
+    assert!(refresh(dir.path(), &mut list, now).await);
+    assert_eq!(list.domains, vec!["casino.example", "poker.example"]);
+    assert!(!is_due(&list, now + Duration::minutes(59)));
+    assert!(is_due(&list, now + Duration::minutes(60)));
+
+    assert!(!refresh(dir.path(), &mut list, now).await);
+
+    std::fs::write(&path, "")?;
+    assert!(!refresh(dir.path(), &mut list, now).await);
+    assert_eq!(list.domains, vec!["casino.example", "poker.example"]);
+    assert!(list.source.unwrap().last_error.is_some());
+
+    // Files outside the configuration directory can't be read.
+    let other = TempDir::new("penguin-subscription-test")?;
+    std::fs::write(other.path().join("hosts"), "0.0.0.0 casino.example\n")?;
+    for location in [
+      other.path().join("hosts").display().to_string(),
+      "../".to_owned() + other.path().file_name().unwrap().to_str().unwrap() + "/hosts",
+    ] {
+      let mut outside = subscribed(&location, ListFormat::Hosts);
+      assert!(!refresh(dir.path(), &mut outside, now).await);
+      assert!(outside.domains.is_empty());
+      assert!(outside.source.unwrap().last_error.is_some());
+    }
+
+    Ok(())
+  }
+
+  async fn adblock_list(headers: HeaderMap) -> impl IntoResponse {
+    if headers
+      .get(header::IF_NONE_MATCH)
+      .is_some_and(|v| v == "\"v1\"")
+    {
+      return StatusCode::NOT_MODIFIED.into_response();
+    }
+    (
+      [(header::ETAG, "\"v1\"")],
+      "! Gambling\n||casino.example^\n||poker.example^\n",
+    )
+      .into_response()
+  }
+
+  #[tokio::test]
+  async fn http_source() -> Result<()> {
+    let dir = TempDir::new("penguin-subscription-test")?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let app = Router::new().route("/list.txt", routing::get(adblock_list));
+    tokio::spawn(axum::Server::from_tcp(listener)?.serve(app.into_make_service()));
+
+    let mut list = subscribed(&format!("http://{}/list.txt", addr), ListFormat::Adblock);
+    let now = Utc::now();
+    assert!(refresh(dir.path(), &mut list, now).await);
+    assert_eq!(list.domains, vec![".casino.example", ".poker.example"]);
+    let source = list.source.clone().unwrap();
+    assert_eq!(source.etag, Some("\"v1\"".to_owned()));
+    assert_eq!(source.last_updated_utc, Some(now));
+
+    // The server says the list hasn't changed.
+    let later = now + Duration::hours(1);
+    assert!(!refresh(dir.path(), &mut list, later).await);
+    let source = list.source.clone().unwrap();
+    assert_eq!(source.last_checked_utc, Some(later));
+    assert_eq!(source.last_updated_utc, Some(now));
+    assert_eq!(source.last_error, None);
+
+    let mut missing = subscribed(&format!("http://{}/missing", addr), ListFormat::Plain);
+    assert!(!refresh(dir.path(), &mut missing, now).await);
+    assert!(missing.source.unwrap().last_error.is_some());
+
+    Ok(())
+  }
+}
@@ -0,0 +1,387 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use tempdir::TempDir;
+
+use crate::domain::flatten;
+use crate::generate::ConfigFiles;
+use crate::list::{Identifiable, IdentifiedList};
+use crate::model::{Client, DomainList, Lease, Rule, RuleKind};
+use crate::quota::ExhaustedQuotas;
+
+/// Answers for names that are blocked, so they don't resolve.
+const BLOCK: &str = "always_nxdomain";
+/// Answers for names that are allowed, which resolve as normal even inside a blocked zone.
+const ALLOW: &str = "always_transparent";
+
+/// Request that unbound reload its configuration. Like squid, this needs an entry in
+/// /etc/sudoers.
+pub fn reload_config() {
+  let output = Command::new("sudo")
+    .args(["pkill", "-HUP", "unbound"])
+    .output();
+  if let Err(e) = output {
+    tracing::error!("Failed to HUP unbound: {:?}", e);
+  } else {
+    tracing::info!("Successfully sent HUP to unbound");
+  }
+}
+
+/// Checks a generated configuration directory with `unbound-checkconf`, using an unbound.conf that
+/// includes just the generated files.
+pub fn check_config(checkconf_binary: &str, dir: &Path) -> Result<()> {
+  let check_dir = TempDir::new("penguin-unbound-check")?;
+  let unbound_conf = check_dir.path().join("unbound.conf");
+  std::fs::write(
+    &unbound_conf,
+    format!("include: \"{}\"\n", dir.join("*.conf").display()),
+  )?;
+
+  let output = Command::new(checkconf_binary)
+    .arg(&unbound_conf)
+    .output()
+    .with_context(|| format!("Failed to run {}", checkconf_binary))?;
+  if output.status.success() {
+    return Ok(());
+  }
+
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  Err(anyhow!(
+    "Unbound rejected the configuration: {}",
+    stderr.trim()
+  ))
+}
+
+/// The zone unbound uses for a dstdomain entry. DNS can't tell a domain from its subdomains, so
+/// "example.com" and ".example.com" both block the domain and everything under it. Addresses
+/// can't be blocked by name, so they have no zone.
+fn zone(entry: &str) -> Option<String> {
+  let domain = entry.trim_start_matches('.');
+  if domain.is_empty() || domain.parse::<IpAddr>().is_ok() {
+    return None;
+  }
+  Some(format!("{}.", domain))
+}
+
+/// The local zones of a client's view, and how unbound answers for each. More specific zones take
+/// precedence in unbound, so setting a zone replaces whatever it was set to before, and the order
+/// they're set in mirrors the order of the http_access lines squid uses.
+#[derive(Default)]
+struct Zones(BTreeMap<String, &'static str>);
+
+impl Zones {
+  fn set_domains(&mut self, domains: &[String], answer: &'static str) {
+    for zone in domains.iter().filter_map(|d| zone(d)) {
+      self.0.insert(zone, answer);
+    }
+  }
+
+  fn set_list(
+    &mut self,
+    domainlists: &IdentifiedList<DomainList>,
+    id: u32,
+    answer: &'static str,
+  ) -> Result<()> {
+    let Some(list) = domainlists.items.iter().find(|l| l.id == Some(id)) else {
+      return Ok(());
+    };
+    // Regex lists match more than names, so DNS can't enforce them.
+    let flattened = flatten(domainlists, list).map_err(|e| anyhow!(e))?;
+    if flattened.kind.is_dstdomain() {
+      self.set_domains(&flattened.domains, answer);
+    }
+    Ok(())
+  }
+}
+
+fn netblock(ip: &str) -> String {
+  if ip.contains('/') {
+    ip.to_owned()
+  } else if ip.contains(':') {
+    format!("{}/128", ip)
+  } else {
+    format!("{}/32", ip)
+  }
+}
+
+/// Works out which zones to block and allow for a client. Unbound has no notion of time, so
+/// scheduled rules are left to squid.
+fn client_zones(
+  client: &Client,
+  domainlists: &IdentifiedList<DomainList>,
+  leases: &[&Lease],
+  exhausted_quotas: &ExhaustedQuotas,
+) -> Result<Zones> {
+  let mut zones = Zones::default();
+  let rules: Vec<(usize, &Rule)> = client
+    .rules
+    .iter()
+    .enumerate()
+    .filter(|(_, r)| r.schedule.is_none())
+    .collect();
+
+  // A deny lease without any domains or domain lists denies everything.
+  if leases.iter().any(|l| {
+    l.rule.kind == RuleKind::DenyHttpAccess
+      && l.rule.domainlists.is_empty()
+      && l.rule.domains.is_empty()
+  }) {
+    zones.0.insert(".".to_owned(), BLOCK);
+    return Ok(zones);
+  }
+
+  let allows: Vec<_> = rules
+    .iter()
+    .filter(|(_, r)| r.kind == RuleKind::AllowHttpAccess)
+    .collect();
+  if !allows.is_empty() {
+    zones.0.insert(".".to_owned(), BLOCK);
+    for (_, rule) in allows {
+      for id in rule.domainlists.iter() {
+        zones.set_list(domainlists, *id, ALLOW)?;
+      }
+    }
+  }
+
+  let denies: Vec<_> = rules
+    .iter()
+    .filter(|(index, r)| match r.kind {
+      RuleKind::DenyHttpAccess => true,
+      RuleKind::QuotaHttpAccess => exhausted_quotas.contains(&(client.id.unwrap(), *index)),
+      RuleKind::AllowHttpAccess => false,
+    })
+    .collect();
+  for (_, rule) in denies.iter() {
+    for id in rule.domainlists.iter() {
+      zones.set_list(domainlists, *id, BLOCK)?;
+    }
+  }
+  for (_, rule) in denies.iter() {
+    zones.set_domains(&rule.exception_domains, ALLOW);
+    for id in rule.exception_domainlists.iter() {
+      zones.set_list(domainlists, *id, ALLOW)?;
+    }
+  }
+
+  // Leases take precedence over rules, and deny leases over allow leases.
+  for kind in [RuleKind::AllowHttpAccess, RuleKind::DenyHttpAccess] {
+    let answer = if kind == RuleKind::AllowHttpAccess {
+      ALLOW
+    } else {
+      BLOCK
+    };
+    for lease in leases.iter().filter(|l| l.rule.kind == kind) {
+      zones.set_domains(&lease.rule.domains, answer);
+      for id in lease.rule.domainlists.iter() {
+        zones.set_list(domainlists, *id, answer)?;
+      }
+    }
+  }
+
+  Ok(zones)
+}
+
+/// Generates unbound configuration that gives each client a view of DNS with the domains it's
+/// denied removed.
+pub fn generate_unbound_config(
+  clients: &IdentifiedList<Client>,
+  domainlists: &IdentifiedList<DomainList>,
+  global_leases: &IdentifiedList<Lease>,
+  exhausted_quotas: &ExhaustedQuotas,
+  block_page_host: Option<&str>,
+) -> Result<ConfigFiles> {
+  let mut files = ConfigFiles::new();
+  let now = Utc::now();
+
+  for client in clients.items.iter() {
+    let leases: Vec<_> = client
+      .leases
+      .iter()
+      .chain(global_leases.items.iter())
+      .filter(|l| l.is_active(now))
+      .collect();
+    let mut zones = client_zones(client, domainlists, &leases, exhausted_quotas)?;
+    // Clients that can't resolve anything still need to resolve the block page.
+    if let Some(host) = block_page_host {
+      if zones.0.get(".") == Some(&BLOCK) {
+        zones.set_domains(&[host.to_owned()], ALLOW);
+      }
+    }
+    if zones.0.is_empty() {
+      continue;
+    }
+
+    let name = format!("client_{:0>4}", client.id().unwrap());
+    let mut lines = vec![
+      "server:".to_owned(),
+      format!("  access-control-view: {} {}", netblock(&client.ip), name),
+      "view:".to_owned(),
+      format!("  name: \"{}\"", name),
+      "  view-first: yes".to_owned(),
+    ];
+    for (zone, answer) in zones.0.iter() {
+      lines.push(format!("  local-zone: \"{}\" {}", zone, answer));
+    }
+    lines.push(String::new());
+    files.insert(format!("{}.conf", name), lines.join("\n"));
+  }
+
+  // Like squid, unbound is happier including a directory that has something in it.
+  files.insert(
+    "client_dummy.conf".to_owned(),
+    "# This file is intentionally left blank.\n".to_owned(),
+  );
+
+  Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::model::{ListKind, Schedule};
+  use chrono::Duration;
+
+  fn rule(kind: RuleKind, domainlists: Vec<u32>) -> Rule {
+    Rule {
+      kind,
+      domainlists,
+      domains: vec![],
+      exception_domains: vec![],
+      exception_domainlists: vec![],
+      mime_types: vec![],
+      file_extensions: vec![],
+      schedule: None,
+      daily_quota_minutes: None,
+    }
+  }
+
+  fn client(rules: Vec<Rule>, leases: Vec<Lease>) -> Client {
+    Client {
+      id: Some(1),
+      ip: "192.168.1.33".to_owned(),
+      name: "Laptop".to_owned(),
+      rules,
+      leases,
+      mac_address: None,
+      bandwidth: None,
+      safe_search: false,
+    }
+  }
+
+  #[allow(deprecated)]
+  fn lease(rule: Rule) -> Lease {
+    Lease {
+      id: None,
+      start_date_utc: None,
+      end_date_utc: Some(Utc::now() + Duration::hours(1)),
+      end_date: None,
+      recurrence: None,
+      rule,
+      granted_by: None,
+      created_date_utc: None,
+    }
+  }
+
+  fn domainlists() -> IdentifiedList<DomainList> {
+    let list = |id: u32, kind: ListKind, domains: &[&str]| DomainList {
+      id: Some(id),
+      name: format!("list{}", id),
+      kind,
+      domains: domains.iter().map(|d| d.to_string()).collect(),
+      includes: vec![],
+      source: None,
+    };
+    IdentifiedList::new(vec![
+      list(1, ListKind::Dstdomain, &[".youtube.com", "vimeo.com"]),
+      list(2, ListKind::Dstdomain, &["kids.youtube.com"]),
+      list(3, ListKind::UrlpathRegex, &["^/shorts/"]),
+      list(4, ListKind::Dstdomain, &[".khanacademy.org", "10.0.0.1"]),
+    ])
+  }
+
+  fn generate(client: Client) -> Result<String> {
+    let files = generate_unbound_config(
+      &IdentifiedList::new(vec![client]),
+      &domainlists(),
+      &IdentifiedList::new(vec![]),
+      &ExhaustedQuotas::new(),
+      Some("penguin.lan"),
+    )?;
+    Ok(files.get("client_0001.conf").cloned().unwrap_or_default())
+  }
+
+  #[test]
+  fn deny_rules() -> Result<()> {
+    let mut videos = rule(RuleKind::DenyHttpAccess, vec![1, 3]);
+    videos.exception_domainlists = vec![2];
+    let mut bedtime = rule(RuleKind::DenyHttpAccess, vec![4]);
+    bedtime.schedule = Some(Schedule {
+      days: vec![],
+      times: vec![],
+      timezone: None,
+    });
+
+    assert_eq!(
+      generate(client(vec![videos, bedtime], vec![]))?,
+      "server:\n\
+       \x20 access-control-view: 192.168.1.33/32 client_0001\n\
+       view:\n\
+       \x20 name: \"client_0001\"\n\
+       \x20 view-first: yes\n\
+       \x20 local-zone: \"kids.youtube.com.\" always_transparent\n\
+       \x20 local-zone: \"vimeo.com.\" always_nxdomain\n\
+       \x20 local-zone: \"youtube.com.\" always_nxdomain\n"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn allowlist_and_leases() -> Result<()> {
+    let mut homework = lease(rule(RuleKind::AllowHttpAccess, vec![]));
+    homework.rule.domains = vec!["scratch.mit.edu".to_owned()];
+    let no_videos = lease(rule(RuleKind::DenyHttpAccess, vec![1]));
+
+    assert_eq!(
+      generate(client(
+        vec![rule(RuleKind::AllowHttpAccess, vec![4])],
+        vec![homework, no_videos]
+      ))?,
+      "server:\n\
+       \x20 access-control-view: 192.168.1.33/32 client_0001\n\
+       view:\n\
+       \x20 name: \"client_0001\"\n\
+       \x20 view-first: yes\n\
+       \x20 local-zone: \".\" always_nxdomain\n\
+       \x20 local-zone: \"khanacademy.org.\" always_transparent\n\
+       \x20 local-zone: \"penguin.lan.\" always_transparent\n\
+       \x20 local-zone: \"scratch.mit.edu.\" always_transparent\n\
+       \x20 local-zone: \"vimeo.com.\" always_nxdomain\n\
+       \x20 local-zone: \"youtube.com.\" always_nxdomain\n"
+    );
+
+    // Grounded
+    assert_eq!(
+      generate(client(
+        vec![rule(RuleKind::DenyHttpAccess, vec![1])],
+        vec![lease(rule(RuleKind::DenyHttpAccess, vec![]))]
+      ))?,
+      "server:\n\
+       \x20 access-control-view: 192.168.1.33/32 client_0001\n\
+       view:\n\
+       \x20 name: \"client_0001\"\n\
+       \x20 view-first: yes\n\
+       \x20 local-zone: \".\" always_nxdomain\n\
+       \x20 local-zone: \"penguin.lan.\" always_transparent\n"
+    );
+
+    // Nothing to enforce
+    assert_eq!(generate(client(vec![], vec![]))?, "");
+
+    Ok(())
+  }
+}